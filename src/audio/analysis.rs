@@ -0,0 +1,191 @@
+use std::f64::consts::PI;
+
+use super::Bus;
+
+/// Levels of each bus after its effects, covering the last `FFT_SIZE` frames
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    buses: [BusAnalysis; Bus::COUNT],
+    /// Width in Hz of each spectrum bin
+    pub bin_hz: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BusAnalysis {
+    pub rms: f32,
+    /// Amplitude of each frequency bin from 0 Hz up to half the sample rate
+    pub spectrum: Vec<f32>,
+}
+
+impl Analysis {
+    pub fn bus(&self, bus: Bus) -> &BusAnalysis {
+        &self.buses[bus.index()]
+    }
+}
+
+/// The last `FFT_SIZE` frames of every bus, oldest first. Sent from the audio
+/// thread to be analysed and then sent back to be reused.
+pub struct AnalysisBlock {
+    buses: Box<[[f64; FFT_SIZE]; Bus::COUNT]>,
+    sample_rate: f64,
+}
+
+/// Collects bus output on the audio thread and hands over a block to be
+/// analysed `ANALYSIS_RATE` times a second. Blocks are allocated up front and
+/// recycled so the callback never allocates, an analysis is skipped if none
+/// have come back yet.
+pub struct Analyser {
+    history: [Vec<f64>; Bus::COUNT],
+    position: usize,
+    frames_per_analysis: usize,
+    frames_since_analysis: usize,
+    sample_rate: f64,
+    free: Vec<AnalysisBlock>,
+}
+
+impl Analyser {
+    pub fn new(sample_rate: f64) -> Self {
+        let free = (0..ANALYSIS_BLOCKS)
+            .map(|_| AnalysisBlock {
+                buses: Box::new([[0.0; FFT_SIZE]; Bus::COUNT]),
+                sample_rate,
+            })
+            .collect();
+        Analyser {
+            history: std::array::from_fn(|_| vec![0.0; FFT_SIZE]),
+            position: 0,
+            frames_per_analysis: (sample_rate / ANALYSIS_RATE) as usize,
+            frames_since_analysis: 0,
+            sample_rate,
+            free,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.frames_per_analysis = (sample_rate / ANALYSIS_RATE) as usize;
+    }
+
+    /// Returns a block when an analysis is due
+    pub fn push(&mut self, bus_samples: &[f64; Bus::COUNT]) -> Option<AnalysisBlock> {
+        for (history, sample) in self.history.iter_mut().zip(bus_samples) {
+            history[self.position] = *sample;
+        }
+        self.position = (self.position + 1) % FFT_SIZE;
+        self.frames_since_analysis += 1;
+        if self.frames_since_analysis < self.frames_per_analysis {
+            return None;
+        }
+        self.frames_since_analysis = 0;
+        let mut block = self.free.pop()?;
+        for (samples, history) in block.buses.iter_mut().zip(&self.history) {
+            let (newer, older) = history.split_at(self.position);
+            samples[..older.len()].copy_from_slice(older);
+            samples[older.len()..].copy_from_slice(newer);
+        }
+        block.sample_rate = self.sample_rate;
+        Some(block)
+    }
+
+    /// Take back a block once it has been analysed
+    pub fn recycle(&mut self, block: AnalysisBlock) {
+        if self.free.len() < ANALYSIS_BLOCKS {
+            self.free.push(block);
+        }
+    }
+}
+
+/// Turns blocks from the audio thread into an `Analysis` off the audio
+/// thread, reusing its FFT buffers
+#[derive(Debug)]
+pub struct SpectrumAnalyser {
+    window: Vec<f64>,
+    // Scales the spectrum so a full scale sine in the middle of a bin reads
+    // as 1
+    scale: f64,
+    re: Vec<f64>,
+    im: Vec<f64>,
+}
+
+impl SpectrumAnalyser {
+    pub fn new() -> Self {
+        let window: Vec<f64> = (0..FFT_SIZE)
+            .map(|i| (PI * i as f64 / FFT_SIZE as f64).sin().powi(2))
+            .collect();
+        SpectrumAnalyser {
+            scale: 2.0 / window.iter().sum::<f64>(),
+            window,
+            re: vec![0.0; FFT_SIZE],
+            im: vec![0.0; FFT_SIZE],
+        }
+    }
+
+    pub fn analyse(&mut self, block: &AnalysisBlock) -> Analysis {
+        Analysis {
+            buses: std::array::from_fn(|i| self.analyse_bus(&block.buses[i])),
+            bin_hz: (block.sample_rate / FFT_SIZE as f64) as f32,
+        }
+    }
+
+    fn analyse_bus(&mut self, samples: &[f64; FFT_SIZE]) -> BusAnalysis {
+        let mut sum_squares = 0.0;
+        for ((re, sample), window) in self.re.iter_mut().zip(samples).zip(&self.window) {
+            sum_squares += sample * sample;
+            *re = sample * window;
+        }
+        self.im.fill(0.0);
+        fft(&mut self.re, &mut self.im);
+
+        let spectrum = self.re[..FFT_SIZE / 2]
+            .iter()
+            .zip(&self.im)
+            .map(|(re, im)| ((re * re + im * im).sqrt() * self.scale) as f32)
+            .collect();
+        BusAnalysis {
+            rms: (sum_squares / FFT_SIZE as f64).sqrt() as f32,
+            spectrum,
+        }
+    }
+}
+
+/// In place iterative radix-2 FFT, the length must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+const FFT_SIZE: usize = 1024;
+const ANALYSIS_RATE: f64 = 30.0;
+// Blocks that can be waiting to be analysed at once
+const ANALYSIS_BLOCKS: usize = 3;
@@ -0,0 +1,713 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{
+    self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use cpal::SupportedStreamConfigRange;
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+    StreamError, SupportedStreamConfig,
+};
+use tracing::{debug, error, warn};
+
+use crate::config::{
+    AUDIO_BACKEND, AUDIO_BACKEND_ENV, AUDIO_CAPTURE_FILE, SIMULATED_BLOCK_FRAMES,
+    SIMULATED_SAMPLE_RATE,
+};
+use crate::resource::audio::WavWriter;
+
+use super::analysis::AnalysisBlock;
+use super::mixer::Mixer;
+use super::{Garbage, MixerEvent, MixerMessage};
+
+/// Somewhere for the mixer's output to go. Runs on the audio thread until a
+/// shutdown message is received.
+pub trait AudioOutput: Send {
+    fn run(
+        &mut self,
+        receiver: Receiver<MixerMessage>,
+        events: SyncSender<MixerEvent>,
+        analysis: SyncSender<AnalysisBlock>,
+    );
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputKind {
+    /// A device name, or None for the system default
+    Cpal(Option<String>),
+    Null,
+    File(String),
+    /// Only renders when the game advances it, so what is heard, or captured
+    /// to the optional file, depends on the order of messages and not on
+    /// wall clock time
+    Stepped(Option<String>),
+}
+
+impl OutputKind {
+    /// The `AUDIO_BACKEND` environment variable takes priority over config.
+    /// Accepts "cpal", "cpal:<device name>", "null", "file", "file:<path>",
+    /// "stepped" or "stepped:<path>".
+    pub fn from_config() -> Self {
+        let backend = env::var(AUDIO_BACKEND_ENV).unwrap_or(AUDIO_BACKEND.to_string());
+        match Self::parse(&backend) {
+            Some(kind) => kind,
+            None => {
+                warn!(backend = backend, "Unknown audio backend, using cpal");
+                OutputKind::Cpal(None)
+            }
+        }
+    }
+
+    fn parse(backend: &str) -> Option<Self> {
+        match backend.split_once(":") {
+            Some(("file", path)) => Some(OutputKind::File(path.to_string())),
+            Some(("cpal", device)) => Some(OutputKind::Cpal(Some(device.to_string()))),
+            Some(("stepped", path)) => Some(OutputKind::Stepped(Some(path.to_string()))),
+            Some(_) => None,
+            None => match backend {
+                "cpal" => Some(OutputKind::Cpal(None)),
+                "null" => Some(OutputKind::Null),
+                "file" => Some(OutputKind::File(AUDIO_CAPTURE_FILE.to_string())),
+                "stepped" => Some(OutputKind::Stepped(None)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Falls back to the null output if there is no usable sound card
+pub fn create_output(kind: OutputKind) -> Box<dyn AudioOutput> {
+    match kind {
+        OutputKind::Cpal(device) => match CpalOutput::new(device) {
+            Ok(output) => Box::new(output),
+            Err(e) => {
+                warn!(err = e.to_string(), "No audio device, using null output");
+                Box::new(SimulatedOutput::null())
+            }
+        },
+        OutputKind::Null => Box::new(SimulatedOutput::null()),
+        OutputKind::File(path) => match SimulatedOutput::file(&path) {
+            Ok(output) => Box::new(output),
+            Err(e) => {
+                error!(
+                    err = e.to_string(),
+                    path = path,
+                    "Failed to open audio capture"
+                );
+                Box::new(SimulatedOutput::null())
+            }
+        },
+        OutputKind::Stepped(None) => Box::new(SimulatedOutput::null().stepped()),
+        OutputKind::Stepped(Some(path)) => match SimulatedOutput::file(&path) {
+            Ok(output) => Box::new(output.stepped()),
+            Err(e) => {
+                error!(
+                    err = e.to_string(),
+                    path = path,
+                    "Failed to open audio capture"
+                );
+                Box::new(SimulatedOutput::null().stepped())
+            }
+        },
+    }
+}
+
+/// Names of the output devices on the default host
+pub fn output_devices() -> Result<Vec<String>> {
+    let host = cpal::default_host();
+    let names = host
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .collect();
+    Ok(names)
+}
+
+/// Plays through a sound card. The stream callback owns the mixer and hands
+/// it back when the stream is dropped, so a new stream can be built when the
+/// device changes or fails without losing track positions.
+pub struct CpalOutput {
+    // The device asked for, None follows the system default device
+    requested: Option<String>,
+}
+
+struct OpenStream {
+    // Dropping the stream stops it
+    _stream: Stream,
+    // The device actually open, the default if the requested one was missing
+    device_name: String,
+    // The system default when last checked
+    default_name: Option<String>,
+    sample_rate: f64,
+}
+
+/// Gives the mixer back to the output thread when the stream callback that
+/// owns it is dropped
+struct MixerSlot {
+    mixer: Option<Mixer>,
+    home: Sender<Mixer>,
+}
+
+impl Drop for MixerSlot {
+    fn drop(&mut self) {
+        if let Some(mixer) = self.mixer.take() {
+            let _ = self.home.send(mixer);
+        }
+    }
+}
+
+impl CpalOutput {
+    /// Only fails if there is no output device at all
+    fn new(requested: Option<String>) -> Result<Self> {
+        find_device_or_default(requested.as_deref())?;
+        Ok(CpalOutput { requested })
+    }
+
+    /// Open the chosen device, falling back to the default if it has gone.
+    /// The mixer moves into the new stream's callback, `keep_rate` is the
+    /// rate of the last stream if there was one.
+    fn open(
+        &self,
+        mixer: &mut Option<Mixer>,
+        keep_rate: Option<f64>,
+        home: &Sender<Mixer>,
+        failed: &Arc<AtomicBool>,
+        events: &SyncSender<MixerEvent>,
+    ) -> Result<OpenStream> {
+        let default_name = default_device_name();
+        let device = find_device_or_default(self.requested.as_deref())?;
+        let device_name = device.name()?;
+
+        let supported_config: Vec<SupportedStreamConfigRange> =
+            device.supported_output_configs()?.collect();
+        let default_config = device.default_output_config()?;
+        // Keep the last stream's rate if the new device supports it
+        let config = match keep_rate {
+            Some(rate) => supported_config
+                .iter()
+                .find(|c| {
+                    c.channels() == default_config.channels()
+                        && c.sample_format() == default_config.sample_format()
+                        && c.min_sample_rate().0 as f64 <= rate
+                        && c.max_sample_rate().0 as f64 >= rate
+                })
+                .map(|c| c.with_sample_rate(SampleRate(rate as u32)))
+                .unwrap_or(default_config),
+            None => default_config,
+        };
+        debug!(
+            device = device_name,
+            configs = format!("{:?}", &supported_config),
+            config = format!("{:?}", &config),
+            "Output device"
+        );
+
+        let sample_rate = config.sample_rate().0 as f64;
+        let Some(mut owned) = mixer.take() else {
+            return Err(anyhow!("No mixer to play"));
+        };
+        // Effects sent so far were built for the old rate
+        owned.receive_messages();
+        owned.set_sample_rate(sample_rate);
+        let mixer = MixerSlot {
+            mixer: Some(owned),
+            home: home.clone(),
+        };
+
+        let stream = match config.sample_format() {
+            SampleFormat::I8 => build_stream::<i8>(&device, &config, mixer, failed, events),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer, failed, events),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, mixer, failed, events),
+            SampleFormat::I64 => build_stream::<i64>(&device, &config, mixer, failed, events),
+            SampleFormat::U8 => build_stream::<u8>(&device, &config, mixer, failed, events),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer, failed, events),
+            SampleFormat::U32 => build_stream::<u32>(&device, &config, mixer, failed, events),
+            SampleFormat::U64 => build_stream::<u64>(&device, &config, mixer, failed, events),
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer, failed, events),
+            SampleFormat::F64 => build_stream::<f64>(&device, &config, mixer, failed, events),
+            sample_format => Err(anyhow!("Unsupported sample format '{sample_format}'")),
+        }?;
+        stream.play()?;
+        Ok(OpenStream {
+            _stream: stream,
+            device_name,
+            default_name,
+            sample_rate,
+        })
+    }
+
+    /// cpal can't report devices coming and going, so a new system default
+    /// is taken as the sign that they have. Only the default is looked up
+    /// here, the full list is only searched when the stream is rebuilt.
+    fn needs_rebuild(&self, stream: &mut Option<OpenStream>, failed: &AtomicBool) -> bool {
+        let Some(stream) = stream else {
+            return true;
+        };
+        if failed.swap(false, Ordering::Relaxed) {
+            return true;
+        }
+        let default_name = default_device_name();
+        if default_name == stream.default_name {
+            return false;
+        }
+        stream.default_name = default_name;
+        // Follow the new default, or try again for a requested device that
+        // was missing when the stream was opened
+        match &self.requested {
+            Some(requested) => *requested != stream.device_name,
+            None => true,
+        }
+    }
+}
+
+impl AudioOutput for CpalOutput {
+    fn run(
+        &mut self,
+        receiver: Receiver<MixerMessage>,
+        events: SyncSender<MixerEvent>,
+        analysis: SyncSender<AnalysisBlock>,
+    ) {
+        // Bounded so the callback's receives never free anything
+        let (mut sender, mixer_receiver) = mpsc::sync_channel(MIXER_QUEUE_LENGTH);
+        let (garbage, garbage_rec) = mpsc::sync_channel::<Garbage>(GARBAGE_QUEUE_LENGTH);
+        // The mixer's rate, effects are built for it before being sent on
+        let mut sample_rate = SIMULATED_SAMPLE_RATE as f64;
+        // Held here only while there is no stream
+        let mut mixer = Some(Mixer::new(
+            mixer_receiver,
+            events.clone(),
+            analysis.clone(),
+            garbage.clone(),
+            sample_rate,
+        ));
+        // Prepared messages waiting for room in the mixer's queue
+        let mut backlog = VecDeque::new();
+        let (home, returned) = mpsc::channel::<Mixer>();
+        let mut stream: Option<OpenStream> = None;
+        let mut opened = false;
+        let failed = Arc::new(AtomicBool::new(false));
+        let mut last_error = None;
+        let mut next_device_check = Instant::now();
+
+        loop {
+            if Instant::now() >= next_device_check {
+                next_device_check += DEVICE_CHECK_INTERVAL;
+                if self.needs_rebuild(&mut stream, &failed) {
+                    // Dropping the old stream sends its mixer back
+                    stream = None;
+                    if mixer.is_none() {
+                        mixer = match returned.recv_timeout(MIXER_RETURN_TIMEOUT) {
+                            Ok(returned) => Some(returned),
+                            Err(_) => {
+                                // Loses track positions but keeps the game audible
+                                error!("Audio stream didn't return the mixer, starting a new one");
+                                let (new_sender, mixer_receiver) =
+                                    mpsc::sync_channel(MIXER_QUEUE_LENGTH);
+                                sender = new_sender;
+                                let (events, analysis) = (events.clone(), analysis.clone());
+                                let garbage = garbage.clone();
+                                Some(Mixer::new(
+                                    mixer_receiver,
+                                    events,
+                                    analysis,
+                                    garbage,
+                                    sample_rate,
+                                ))
+                            }
+                        };
+                    }
+                    // Anything waiting goes in before the mixer is retuned
+                    forward(&mut backlog, &sender, &mut mixer);
+                    let keep_rate = opened.then_some(sample_rate);
+                    match self.open(&mut mixer, keep_rate, &home, &failed, &events) {
+                        Ok(open) => {
+                            debug!(device = open.device_name, "Audio stream started");
+                            sample_rate = open.sample_rate;
+                            opened = true;
+                            stream = Some(open);
+                            last_error = None;
+                        }
+                        Err(e) => {
+                            // Only report each failure once while retrying
+                            let error = e.to_string();
+                            if last_error.as_ref() != Some(&error) {
+                                error!(err = error, "Failed to open audio stream");
+                                let _ = events.try_send(MixerEvent::DeviceError(error.clone()));
+                                last_error = Some(error);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Freed here rather than in the callback
+            while let Ok(garbage) = garbage_rec.try_recv() {
+                garbage.free();
+            }
+
+            let mut timeout = next_device_check.saturating_duration_since(Instant::now());
+            if !backlog.is_empty() {
+                timeout = timeout.min(BACKLOG_RETRY_INTERVAL);
+            }
+            match receiver.recv_timeout(timeout) {
+                Ok(MixerMessage::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+                // The device keeps its own time
+                Ok(MixerMessage::Advance(_)) => (),
+                Ok(message) => {
+                    debug!(
+                        last_message = format!("{:?}", message),
+                        "last audio message"
+                    );
+                    backlog.push_back(message.prepare(sample_rate));
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+            forward(&mut backlog, &sender, &mut mixer);
+        }
+    }
+}
+
+/// Move waiting messages into the mixer's queue while it has room. A mixer
+/// held by the output thread has nothing else draining its queue so takes
+/// them straight away.
+fn forward(
+    backlog: &mut VecDeque<MixerMessage>,
+    sender: &SyncSender<MixerMessage>,
+    mixer: &mut Option<Mixer>,
+) {
+    while let Some(message) = backlog.pop_front() {
+        match sender.try_send(message) {
+            Ok(()) => (),
+            Err(TrySendError::Full(message)) => {
+                backlog.push_front(message);
+                match mixer {
+                    Some(mixer) => mixer.receive_messages(),
+                    None => return,
+                }
+            }
+            // Only if the mixer was lost along with its stream
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+    if let Some(mixer) = mixer {
+        mixer.receive_messages();
+    }
+}
+
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const BACKLOG_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+const MIXER_QUEUE_LENGTH: usize = 1024;
+const GARBAGE_QUEUE_LENGTH: usize = 256;
+// A dropped stream normally hands its mixer back straight away
+const MIXER_RETURN_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn find_device(name: Option<&str>) -> Result<Device> {
+    let host = cpal::default_host();
+    match name {
+        Some(name) => host
+            .output_devices()?
+            .find(|d| d.name().is_ok_and(|n| n == name))
+            .ok_or(anyhow!("No output device named {}", name)),
+        None => host
+            .default_output_device()
+            .ok_or(anyhow!("No default output device")),
+    }
+}
+
+fn default_device_name() -> Option<String> {
+    find_device(None).ok()?.name().ok()
+}
+
+fn find_device_or_default(name: Option<&str>) -> Result<Device> {
+    match find_device(name) {
+        Ok(device) => Ok(device),
+        Err(e) if name.is_some() => {
+            warn!(err = e.to_string(), "Using default output device");
+            find_device(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn build_stream<T>(
+    device: &Device,
+    config: &SupportedStreamConfig,
+    mut mixer: MixerSlot,
+    failed: &Arc<AtomicBool>,
+    events: &SyncSender<MixerEvent>,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let config: StreamConfig = config.config();
+    let channels = config.channels as usize;
+    // Only grows if the device asks for a bigger buffer than last time
+    let mut block: Vec<[f32; 2]> = Vec::with_capacity(MAX_EXPECTED_FRAMES);
+
+    let failed = failed.clone();
+    let events = events.clone();
+    let err_fn = move |err: StreamError| {
+        let _ = events.try_send(MixerEvent::DeviceError(err.to_string()));
+        failed.store(true, Ordering::Relaxed);
+    };
+
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            block.resize(data.len() / channels, [0.0; 2]);
+            match &mut mixer.mixer {
+                Some(mixer) => mixer.render(&mut block),
+                None => block.fill([0.0; 2]),
+            }
+            write_data(data, channels, &block);
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
+
+const MAX_EXPECTED_FRAMES: usize = 4096;
+
+/// Mono devices get both channels mixed, channels past the first two are
+/// left silent
+fn write_data<T>(output: &mut [T], channels: usize, block: &[[f32; 2]])
+where
+    T: Sample + FromSample<f32>,
+{
+    for (frame, [left, right]) in output.chunks_mut(channels).zip(block) {
+        if let [mono] = frame {
+            *mono = T::from_sample((left + right) / 2.0);
+            continue;
+        }
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = match i {
+                0 => T::from_sample(*left),
+                1 => T::from_sample(*right),
+                _ => T::EQUILIBRIUM,
+            };
+        }
+    }
+}
+
+/// Pulls blocks from the mixer at the rate a device would, either throwing
+/// them away or writing them to a wav file. A stepped output renders only the
+/// time it is advanced by, applying messages on the frame after everything
+/// sent before them.
+pub struct SimulatedOutput {
+    sample_rate: u32,
+    writer: Option<WavWriter>,
+    stepped: bool,
+}
+
+impl SimulatedOutput {
+    fn null() -> Self {
+        SimulatedOutput {
+            sample_rate: SIMULATED_SAMPLE_RATE,
+            writer: None,
+            stepped: false,
+        }
+    }
+
+    fn file(path: &str) -> Result<Self> {
+        Ok(SimulatedOutput {
+            sample_rate: SIMULATED_SAMPLE_RATE,
+            writer: Some(WavWriter::create(path, SIMULATED_SAMPLE_RATE, 2)?),
+            stepped: false,
+        })
+    }
+
+    fn stepped(self) -> Self {
+        SimulatedOutput {
+            stepped: true,
+            ..self
+        }
+    }
+
+    fn render(&mut self, mixer: &mut Mixer, block: &mut [[f32; 2]]) {
+        mixer.render(block);
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.write_samples(block.as_flattened()) {
+                error!(err = e.to_string(), "Failed to write audio capture");
+                self.writer = None;
+            }
+        }
+    }
+
+    /// Render the frames owed for the total time advanced, counting whole
+    /// nanoseconds so the frames rendered don't depend on how it was split
+    fn run_stepped(
+        &mut self,
+        receiver: Receiver<MixerMessage>,
+        sender: Sender<MixerMessage>,
+        mut mixer: Mixer,
+    ) {
+        let mut block = vec![[0.0; 2]; SIMULATED_BLOCK_FRAMES];
+        let mut advanced = Duration::ZERO;
+        let mut rendered = 0;
+        loop {
+            match receiver.recv() {
+                Ok(MixerMessage::Shutdown) | Err(_) => {
+                    self.finish();
+                    return;
+                }
+                Ok(MixerMessage::Advance(time)) => {
+                    advanced += time;
+                    let due = advanced.as_nanos() * self.sample_rate as u128 / 1_000_000_000;
+                    while rendered < due {
+                        let frames = (due - rendered).min(SIMULATED_BLOCK_FRAMES as u128);
+                        self.render(&mut mixer, &mut block[..frames as usize]);
+                        rendered += frames;
+                    }
+                }
+                Ok(message) => sender
+                    .send(message.prepare(self.sample_rate as f64))
+                    .unwrap(),
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finish() {
+                error!(err = e.to_string(), "Failed to finish audio capture");
+            }
+        }
+    }
+}
+
+impl AudioOutput for SimulatedOutput {
+    fn run(
+        &mut self,
+        receiver: Receiver<MixerMessage>,
+        events: SyncSender<MixerEvent>,
+        analysis: SyncSender<AnalysisBlock>,
+    ) {
+        let (sender, mixer_receiver) = mpsc::channel::<MixerMessage>();
+        // Nothing here is realtime so what the mixer lets go of is dropped
+        // as it is sent
+        let (garbage, _) = mpsc::sync_channel(0);
+        let sample_rate = self.sample_rate as f64;
+        let mut mixer = Mixer::new(mixer_receiver, events, analysis, garbage, sample_rate);
+        if self.stepped {
+            self.run_stepped(receiver, sender, mixer);
+            return;
+        }
+        let mut block = vec![[0.0; 2]; SIMULATED_BLOCK_FRAMES];
+        let block_duration =
+            Duration::from_secs_f64(SIMULATED_BLOCK_FRAMES as f64 / self.sample_rate as f64);
+        let mut next_block = Instant::now();
+
+        loop {
+            loop {
+                match receiver.try_recv() {
+                    Ok(MixerMessage::Shutdown) | Err(TryRecvError::Disconnected) => {
+                        self.finish();
+                        return;
+                    }
+                    // Paced by the clock instead
+                    Ok(MixerMessage::Advance(_)) => (),
+                    Ok(message) => sender
+                        .send(message.prepare(self.sample_rate as f64))
+                        .unwrap(),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            self.render(&mut mixer, &mut block);
+
+            next_block += block_duration;
+            if let Some(wait) = next_block.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process, slice};
+
+    use super::*;
+    use crate::audio::ids::TrackIds;
+    use crate::audio::{TrackAction, TrackEvent};
+    use crate::resource::audio::Wav;
+
+    /// Play a tenth of a second of wav headless, advancing the output in the
+    /// given steps, and return the events it sent
+    fn run_stepped(kind: OutputKind, steps: &[Duration]) -> Vec<TrackEvent> {
+        let mut output = create_output(kind);
+        let (sender, receiver) = mpsc::channel();
+        let (events, event_rec) = mpsc::sync_channel(16);
+        let (analysis, _analysis_rec) = mpsc::sync_channel(16);
+        let thread = thread::spawn(move || output.run(receiver, events, analysis));
+
+        let wav = Wav {
+            samples: (0..SIMULATED_SAMPLE_RATE / 10)
+                .map(|i| (i as f64 * 0.05).sin())
+                .collect(),
+            sample_rate: SIMULATED_SAMPLE_RATE,
+        };
+        let mut ids = TrackIds::default();
+        let name = "test.wav".to_string();
+        let id = ids.id(&name).unwrap();
+        let messages = [
+            MixerMessage::Wav(id, Arc::new(wav)),
+            ids.message(TrackAction::Play(name)).unwrap(),
+        ];
+        for message in messages {
+            sender.send(message).unwrap();
+        }
+        for step in steps {
+            sender.send(MixerMessage::Advance(*step)).unwrap();
+        }
+        sender.send(MixerMessage::Shutdown).unwrap();
+        thread.join().unwrap();
+        event_rec.try_iter().map(|e| ids.event(e)).collect()
+    }
+
+    #[test]
+    fn stepped_output_only_renders_when_advanced() {
+        let started = TrackEvent::Started("test.wav".to_string());
+        let finished = TrackEvent::Finished("test.wav".to_string());
+
+        // Messages are only applied once there is something to render
+        let events = run_stepped(OutputKind::Stepped(None), &[]);
+        assert!(events.is_empty());
+
+        let events = run_stepped(OutputKind::Stepped(None), &[Duration::from_millis(50)]);
+        assert_eq!(events, slice::from_ref(&started));
+
+        let events = run_stepped(OutputKind::Stepped(None), &[Duration::from_millis(150)]);
+        assert_eq!(events, [started, finished]);
+    }
+
+    #[test]
+    fn stepped_capture_does_not_depend_on_step_size() {
+        // Named by process so test runs at the same time don't share files
+        let dir = env::temp_dir();
+        let whole = dir.join(format!("stepped_capture_whole_{}.wav", process::id()));
+        let split = dir.join(format!("stepped_capture_split_{}.wav", process::id()));
+
+        let kind = OutputKind::Stepped(Some(whole.to_str().unwrap().to_string()));
+        run_stepped(kind, &[Duration::from_millis(120)]);
+        let kind = OutputKind::Stepped(Some(split.to_str().unwrap().to_string()));
+        let steps = [7, 16, 33, 1, 63].map(Duration::from_millis);
+        run_stepped(kind, &steps);
+
+        let whole_bytes = fs::read(&whole).unwrap();
+        let split_bytes = fs::read(&split).unwrap();
+        let _ = fs::remove_file(whole);
+        let _ = fs::remove_file(split);
+        // Header and 120ms of stereo 16 bit frames
+        let frames = SIMULATED_SAMPLE_RATE as usize * 120 / 1000;
+        assert_eq!(whole_bytes.len(), 44 + frames * 4);
+        assert_eq!(whole_bytes, split_bytes);
+    }
+}
@@ -0,0 +1,115 @@
+use std::f64::consts::PI;
+
+use super::ids::TrackId;
+use super::ClickTrack;
+
+/// Synthesises a short decaying tone on every beat row of a map, following
+/// the song time of the track it is attached to or its own clock
+#[derive(Debug)]
+pub struct Clicker {
+    track: Option<TrackId>,
+    bpm: f64,
+    subdivisions: f64,
+    start_offset: f64,
+    // Seconds since the clicker was created, used when there is no track
+    elapsed: f64,
+    last_row: Option<i64>,
+    // Seconds since the last click started, None once it has died away
+    phase: Option<f64>,
+    frequency: f64,
+}
+
+impl Clicker {
+    /// `track` is the id of the click track's track
+    pub fn new(click_track: &ClickTrack, track: Option<TrackId>) -> Self {
+        Clicker {
+            track,
+            bpm: click_track.bpm,
+            subdivisions: click_track.subdivisions,
+            start_offset: click_track.start_offset,
+            elapsed: 0.0,
+            last_row: None,
+            phase: None,
+            frequency: CLICK_FREQUENCY,
+        }
+    }
+
+    pub fn track(&self) -> Option<TrackId> {
+        self.track
+    }
+
+    /// `song_time` is None while the followed track isn't playing, it is
+    /// ignored when there is no track to follow
+    pub fn next_sample(&mut self, song_time: Option<f64>, seconds: f64) -> f64 {
+        let time = match self.track {
+            Some(_) => song_time,
+            None => Some(self.elapsed),
+        };
+        self.elapsed += seconds;
+        if let Some(time) = time {
+            self.check_row(time);
+        }
+        let Some(phase) = self.phase else {
+            return 0.0;
+        };
+        let envelope = (-phase / CLICK_DECAY_SECONDS).exp();
+        let sample = (2.0 * PI * self.frequency * phase).sin() * envelope * CLICK_LEVEL;
+        self.phase = (phase + seconds < CLICK_SECONDS).then_some(phase + seconds);
+        sample
+    }
+
+    fn check_row(&mut self, time: f64) {
+        let Clicker {
+            bpm,
+            subdivisions,
+            start_offset,
+            ..
+        } = *self;
+        // Same spacing as the cubes, row i reaches the player at
+        // (start_offset + i) * 60 / (bpm * subdivisions)
+        let row_seconds = 60.0 / (bpm * subdivisions);
+        let row = (time / row_seconds - start_offset).floor() as i64;
+        // Turning the clicks on part way through a row waits for the next one
+        let last_row = self.last_row.replace(row);
+        if last_row.is_none() || last_row == Some(row) {
+            return;
+        }
+        let on_beat = row.rem_euclid(subdivisions.round().max(1.0) as i64) == 0;
+        self.frequency = if on_beat {
+            CLICK_ACCENT_FREQUENCY
+        } else {
+            CLICK_FREQUENCY
+        };
+        self.phase = Some(0.0);
+    }
+}
+
+/// A single click at a lower pitch, for one off positional sounds
+pub struct Tick {
+    pub gains: [f64; 2],
+    phase: f64,
+}
+
+impl Tick {
+    pub fn new(gains: [f64; 2]) -> Self {
+        Tick { gains, phase: 0.0 }
+    }
+
+    /// None once the tick has died away
+    pub fn next_sample(&mut self, seconds: f64) -> Option<f64> {
+        if self.phase >= CLICK_SECONDS {
+            return None;
+        }
+        let envelope = (-self.phase / CLICK_DECAY_SECONDS).exp();
+        let sample = (2.0 * PI * TICK_FREQUENCY * self.phase).sin() * envelope * CLICK_LEVEL;
+        self.phase += seconds;
+        Some(sample)
+    }
+}
+
+const TICK_FREQUENCY: f64 = 600.0;
+const CLICK_FREQUENCY: f64 = 1_000.0;
+const CLICK_ACCENT_FREQUENCY: f64 = 1_600.0;
+const CLICK_SECONDS: f64 = 0.04;
+const CLICK_DECAY_SECONDS: f64 = 0.008;
+const CLICK_LEVEL: f64 = 0.5;
@@ -0,0 +1,652 @@
+use std::f64::consts::PI;
+use std::fmt::Debug;
+
+use super::ids::EffectId;
+
+pub trait Effect: Send {
+    fn process(&mut self, sample: f64) -> f64;
+    fn param(&self, param: EffectParam) -> Option<f64>;
+    fn set_param(&mut self, param: EffectParam, value: f64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectKind {
+    LowPass {
+        cutoff: f64,
+        q: f64,
+    },
+    HighPass {
+        cutoff: f64,
+        q: f64,
+    },
+    Delay {
+        time: f64,
+        feedback: f64,
+        mix: f64,
+    },
+    Reverb {
+        room_size: f64,
+        damping: f64,
+        mix: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectParam {
+    Cutoff,
+    Q,
+    Time,
+    Feedback,
+    Mix,
+    RoomSize,
+    Damping,
+}
+
+impl EffectParam {
+    const COUNT: usize = 7;
+    const ALL: [EffectParam; Self::COUNT] = [
+        Self::Cutoff,
+        Self::Q,
+        Self::Time,
+        Self::Feedback,
+        Self::Mix,
+        Self::RoomSize,
+        Self::Damping,
+    ];
+}
+
+/// Move `param` to `value` over `ramp` seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Automation {
+    pub param: EffectParam,
+    pub value: f64,
+    pub ramp: f64,
+}
+
+fn create_effect(kind: EffectKind, sample_rate: f64) -> Box<dyn Effect> {
+    match kind {
+        EffectKind::LowPass { cutoff, q } => {
+            Box::new(Biquad::new(FilterType::LowPass, cutoff, q, sample_rate))
+        }
+        EffectKind::HighPass { cutoff, q } => {
+            Box::new(Biquad::new(FilterType::HighPass, cutoff, q, sample_rate))
+        }
+        EffectKind::Delay {
+            time,
+            feedback,
+            mix,
+        } => Box::new(Delay::new(time, feedback, mix, sample_rate)),
+        EffectKind::Reverb {
+            room_size,
+            damping,
+            mix,
+        } => Box::new(Reverb::new(room_size, damping, mix, sample_rate)),
+    }
+}
+
+/// An effect along with what it was built from, so it can be built again
+/// for a new sample rate
+pub struct BuiltEffect {
+    kind: EffectKind,
+    sample_rate: f64,
+    effect: Box<dyn Effect>,
+}
+
+impl BuiltEffect {
+    pub fn new(kind: EffectKind, sample_rate: f64) -> Self {
+        BuiltEffect {
+            kind,
+            sample_rate,
+            effect: create_effect(kind, sample_rate),
+        }
+    }
+}
+
+impl Debug for BuiltEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BuiltEffect({:?}, {:?})", self.kind, self.sample_rate)
+    }
+}
+
+/// Effects run in the order they were added. Adding an effect with an id
+/// that is already in the chain replaces it in place. There is room for
+/// `MAX_EFFECTS` from the start so the chain never allocates in the audio
+/// callback, effects that are replaced, removed or don't fit are handed back
+/// to be freed elsewhere.
+pub struct EffectChain {
+    slots: Vec<Slot>,
+}
+
+impl Default for EffectChain {
+    fn default() -> Self {
+        EffectChain {
+            slots: Vec::with_capacity(MAX_EFFECTS),
+        }
+    }
+}
+
+const MAX_EFFECTS: usize = 8;
+
+struct Slot {
+    id: EffectId,
+    effect: BuiltEffect,
+    // One for each parameter, in the order of `EffectParam::ALL`
+    ramps: [Option<Ramp>; EffectParam::COUNT],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    value: f64,
+    target: f64,
+    // Change per sample
+    step: f64,
+}
+
+impl EffectChain {
+    /// Returns the effect that was replaced, or `effect` if the chain is full
+    pub fn add(&mut self, id: EffectId, effect: BuiltEffect) -> Option<BuiltEffect> {
+        if let Some(slot) = self.slots.iter_mut().find(|s| s.id == id) {
+            slot.ramps = [None; EffectParam::COUNT];
+            return Some(std::mem::replace(&mut slot.effect, effect));
+        }
+        if self.slots.len() == MAX_EFFECTS {
+            return Some(effect);
+        }
+        self.slots.push(Slot {
+            id,
+            effect,
+            ramps: [None; EffectParam::COUNT],
+        });
+        None
+    }
+
+    pub fn remove(&mut self, id: EffectId) -> Option<BuiltEffect> {
+        let i = self.slots.iter().position(|s| s.id == id)?;
+        Some(self.slots.remove(i).effect)
+    }
+
+    /// Empty the chain, keeping its room for effects
+    pub fn drain(&mut self) -> impl Iterator<Item = BuiltEffect> + '_ {
+        self.slots.drain(..).map(|s| s.effect)
+    }
+
+    /// Rebuild effects tuned for another rate, keeping their current
+    /// parameters and the time left on their ramps. Allocates so isn't for
+    /// the audio callback.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        for slot in &mut self.slots {
+            let old_rate = slot.effect.sample_rate;
+            if old_rate == sample_rate {
+                continue;
+            }
+            let mut effect = BuiltEffect::new(slot.effect.kind, sample_rate);
+            for param in EffectParam::ALL {
+                if let Some(value) = slot.effect.effect.param(param) {
+                    effect.effect.set_param(param, value);
+                }
+            }
+            for ramp in slot.ramps.iter_mut().flatten() {
+                ramp.step *= old_rate / sample_rate;
+            }
+            slot.effect = effect;
+        }
+    }
+
+    pub fn automate(&mut self, id: EffectId, automation: Automation, sample_rate: f64) {
+        let Some(slot) = self.slots.iter_mut().find(|s| s.id == id) else {
+            return;
+        };
+        let effect = &mut slot.effect.effect;
+        let Some(value) = effect.param(automation.param) else {
+            return;
+        };
+        let ramp = &mut slot.ramps[automation.param as usize];
+        let samples = automation.ramp * sample_rate;
+        if samples < 1.0 {
+            *ramp = None;
+            effect.set_param(automation.param, automation.value);
+            return;
+        }
+        *ramp = Some(Ramp {
+            value,
+            target: automation.value,
+            step: (automation.value - value) / samples,
+        });
+    }
+
+    pub fn process(&mut self, sample: f64) -> f64 {
+        let mut result = sample;
+        for slot in &mut self.slots {
+            let effect = &mut slot.effect.effect;
+            for (param, ramp) in EffectParam::ALL.into_iter().zip(&mut slot.ramps) {
+                let Some(r) = ramp else {
+                    continue;
+                };
+                r.value += r.step;
+                let finished =
+                    (r.step >= 0.0 && r.value >= r.target) || (r.step < 0.0 && r.value <= r.target);
+                if finished {
+                    r.value = r.target;
+                }
+                effect.set_param(param, r.value);
+                if finished {
+                    *ramp = None;
+                }
+            }
+            result = effect.process(result);
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterType {
+    LowPass,
+    HighPass,
+}
+
+/// Filters from the RBJ audio EQ cookbook
+struct Biquad {
+    filter_type: FilterType,
+    cutoff: f64,
+    q: f64,
+    sample_rate: f64,
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn new(filter_type: FilterType, cutoff: f64, q: f64, sample_rate: f64) -> Self {
+        let mut filter = Biquad {
+            filter_type,
+            cutoff,
+            q,
+            sample_rate,
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        };
+        filter.update_coefficients();
+        filter
+    }
+
+    fn update_coefficients(&mut self) {
+        let nyquist = self.sample_rate / 2.0;
+        let cutoff = self.cutoff.clamp(10.0, nyquist * 0.99);
+        let w0 = 2.0 * PI * cutoff / self.sample_rate;
+        let cos_w0 = w0.cos();
+        let alpha = w0.sin() / (2.0 * f64::max(self.q, 0.01));
+        let (b0, b1, b2) = match self.filter_type {
+            FilterType::LowPass => {
+                let b1 = 1.0 - cos_w0;
+                (b1 / 2.0, b1, b1 / 2.0)
+            }
+            FilterType::HighPass => {
+                let b1 = -(1.0 + cos_w0);
+                (-b1 / 2.0, b1, -b1 / 2.0)
+            }
+        };
+        let a0 = 1.0 + alpha;
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = -2.0 * cos_w0 / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+}
+
+impl Effect for Biquad {
+    fn process(&mut self, sample: f64) -> f64 {
+        let result = self.b0 * sample + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = sample;
+        self.y2 = self.y1;
+        self.y1 = result;
+        result
+    }
+
+    fn param(&self, param: EffectParam) -> Option<f64> {
+        match param {
+            EffectParam::Cutoff => Some(self.cutoff),
+            EffectParam::Q => Some(self.q),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, param: EffectParam, value: f64) {
+        match param {
+            EffectParam::Cutoff => self.cutoff = value,
+            EffectParam::Q => self.q = value,
+            _ => return,
+        }
+        self.update_coefficients();
+    }
+}
+
+struct Delay {
+    buffer: Vec<f64>,
+    position: usize,
+    delay_samples: usize,
+    time: f64,
+    feedback: f64,
+    mix: f64,
+    sample_rate: f64,
+}
+
+impl Delay {
+    fn new(time: f64, feedback: f64, mix: f64, sample_rate: f64) -> Self {
+        let buffer = vec![0.0; (MAX_DELAY_SECONDS * sample_rate) as usize + 1];
+        let mut delay = Delay {
+            buffer,
+            position: 0,
+            delay_samples: 1,
+            time,
+            feedback,
+            mix,
+            sample_rate,
+        };
+        delay.set_param(EffectParam::Time, time);
+        delay
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, sample: f64) -> f64 {
+        let len = self.buffer.len();
+        let read = (self.position + len - self.delay_samples) % len;
+        let delayed = self.buffer[read];
+        self.buffer[self.position] = sample + delayed * self.feedback;
+        self.position = (self.position + 1) % len;
+        sample * (1.0 - self.mix) + delayed * self.mix
+    }
+
+    fn param(&self, param: EffectParam) -> Option<f64> {
+        match param {
+            EffectParam::Time => Some(self.time),
+            EffectParam::Feedback => Some(self.feedback),
+            EffectParam::Mix => Some(self.mix),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, param: EffectParam, value: f64) {
+        match param {
+            EffectParam::Time => {
+                self.time = value.clamp(0.0, MAX_DELAY_SECONDS);
+                let samples = (self.time * self.sample_rate) as usize;
+                self.delay_samples = samples.clamp(1, self.buffer.len() - 1);
+            }
+            EffectParam::Feedback => self.feedback = value.clamp(0.0, 0.95),
+            EffectParam::Mix => self.mix = value.clamp(0.0, 1.0),
+            _ => (),
+        }
+    }
+}
+
+const MAX_DELAY_SECONDS: f64 = 2.0;
+
+/// Mono Freeverb style reverb, parallel damped combs into series allpasses
+struct Reverb {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    room_size: f64,
+    damping: f64,
+    mix: f64,
+}
+
+impl Reverb {
+    fn new(room_size: f64, damping: f64, mix: f64, sample_rate: f64) -> Self {
+        let scale = sample_rate / 44_100.0;
+        let combs = COMB_LENGTHS
+            .iter()
+            .map(|l| Comb::new((*l as f64 * scale) as usize))
+            .collect();
+        let allpasses = ALLPASS_LENGTHS
+            .iter()
+            .map(|l| Allpass::new((*l as f64 * scale) as usize))
+            .collect();
+        let mut reverb = Reverb {
+            combs,
+            allpasses,
+            room_size,
+            damping,
+            mix,
+        };
+        reverb.update_combs();
+        reverb
+    }
+
+    fn update_combs(&mut self) {
+        let feedback = 0.7 + 0.28 * self.room_size.clamp(0.0, 1.0);
+        let damping = self.damping.clamp(0.0, 1.0) * 0.4;
+        for comb in &mut self.combs {
+            comb.feedback = feedback;
+            comb.damping = damping;
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, sample: f64) -> f64 {
+        let input = sample * 0.015;
+        let mut wet: f64 = self.combs.iter_mut().map(|c| c.process(input)).sum();
+        for allpass in &mut self.allpasses {
+            wet = allpass.process(wet);
+        }
+        sample * (1.0 - self.mix) + wet * self.mix * 3.0
+    }
+
+    fn param(&self, param: EffectParam) -> Option<f64> {
+        match param {
+            EffectParam::RoomSize => Some(self.room_size),
+            EffectParam::Damping => Some(self.damping),
+            EffectParam::Mix => Some(self.mix),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, param: EffectParam, value: f64) {
+        match param {
+            EffectParam::RoomSize => self.room_size = value,
+            EffectParam::Damping => self.damping = value,
+            EffectParam::Mix => self.mix = value.clamp(0.0, 1.0),
+            _ => return,
+        }
+        self.update_combs();
+    }
+}
+
+const COMB_LENGTHS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_LENGTHS: [usize; 2] = [556, 441];
+
+struct Comb {
+    buffer: Vec<f64>,
+    position: usize,
+    feedback: f64,
+    damping: f64,
+    filter_store: f64,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Comb {
+            buffer: vec![0.0; length.max(1)],
+            position: 0,
+            feedback: 0.0,
+            damping: 0.0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.buffer[self.position];
+        self.filter_store = output * (1.0 - self.damping) + self.filter_store * self.damping;
+        self.buffer[self.position] = input + self.filter_store * self.feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f64>,
+    position: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Self {
+        Allpass {
+            buffer: vec![0.0; length.max(1)],
+            position: 0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let buffered = self.buffer[self.position];
+        self.buffer[self.position] = input + buffered * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 44_100.0;
+
+    fn run(kind: EffectKind, input: impl Iterator<Item = f64>) -> Vec<f64> {
+        let mut effect = BuiltEffect::new(kind, SAMPLE_RATE).effect;
+        input.map(|sample| effect.process(sample)).collect()
+    }
+
+    fn impulse(seconds: f64) -> impl Iterator<Item = f64> {
+        (0..(seconds * SAMPLE_RATE) as usize).map(|i| if i == 0 { 1.0 } else { 0.0 })
+    }
+
+    /// Peak level of a unit sine at `frequency` once the filter has settled
+    fn sine_gain(kind: EffectKind, frequency: f64) -> f64 {
+        let sine = (0..8820).map(|i| (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin());
+        let output = run(kind, sine);
+        output[4410..].iter().fold(0.0, |a, b| b.abs().max(a))
+    }
+
+    fn energy(samples: &[f64]) -> f64 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn filters_pass_their_side_of_the_cutoff() {
+        let low_pass = EffectKind::LowPass {
+            cutoff: 1_000.0,
+            q: 0.707,
+        };
+        let high_pass = EffectKind::HighPass {
+            cutoff: 1_000.0,
+            q: 0.707,
+        };
+        // A q of 0.707 is Butterworth, 3 dB down at the cutoff
+        assert!((sine_gain(low_pass, 1_000.0) - 0.707).abs() < 0.01);
+        assert!((sine_gain(high_pass, 1_000.0) - 0.707).abs() < 0.01);
+        assert!(sine_gain(low_pass, 100.0) > 0.99);
+        assert!(sine_gain(low_pass, 10_000.0) < 0.02);
+        assert!(sine_gain(high_pass, 10_000.0) > 0.99);
+        assert!(sine_gain(high_pass, 100.0) < 0.02);
+    }
+
+    #[test]
+    fn delay_repeats_quieter_each_time() {
+        let kind = EffectKind::Delay {
+            time: 0.01,
+            feedback: 0.5,
+            mix: 0.5,
+        };
+        let output = run(kind, impulse(0.05));
+        let delay = 441;
+        assert_eq!(output[0], 0.5);
+        assert_eq!(output[delay], 0.5);
+        assert_eq!(output[delay * 2], 0.25);
+        assert_eq!(output[delay * 3], 0.125);
+        let silent = [1..delay, delay + 1..delay * 2];
+        assert!(silent.into_iter().flatten().all(|i| output[i] == 0.0));
+    }
+
+    #[test]
+    fn reverb_tails_die_away_slower_in_bigger_rooms() {
+        let reverb = |room_size| EffectKind::Reverb {
+            room_size,
+            damping: 0.3,
+            mix: 1.0,
+        };
+        let half_second = SAMPLE_RATE as usize / 2;
+        let big = run(reverb(0.8), impulse(2.0));
+        let windows: Vec<f64> = big.chunks(half_second).map(energy).collect();
+        assert!(windows.windows(2).all(|w| w[1] < w[0]));
+        assert!(windows[3] > 0.0 && windows[3] < windows[0] * 0.01);
+
+        let small = run(reverb(0.2), impulse(2.0));
+        let tail = half_second..half_second * 2;
+        assert!(energy(&small[tail.clone()]) < energy(&big[tail]) * 0.1);
+    }
+
+    #[test]
+    fn automation_ramps_to_its_target() {
+        let mut chain = EffectChain::default();
+        let id = EffectId(0);
+        let kind = EffectKind::LowPass {
+            cutoff: 1_000.0,
+            q: 0.707,
+        };
+        assert!(chain.add(id, BuiltEffect::new(kind, SAMPLE_RATE)).is_none());
+        let automation = Automation {
+            param: EffectParam::Cutoff,
+            value: 2_000.0,
+            ramp: 0.01,
+        };
+        chain.automate(id, automation, SAMPLE_RATE);
+        let cutoff = |chain: &EffectChain| chain.slots[0].effect.effect.param(EffectParam::Cutoff);
+
+        (0..220).for_each(|_| _ = chain.process(0.0));
+        assert!((cutoff(&chain).unwrap() - 1_500.0).abs() < 10.0);
+        (0..222).for_each(|_| _ = chain.process(0.0));
+        assert_eq!(cutoff(&chain), Some(2_000.0));
+        assert!(chain.slots[0].ramps.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn chains_hand_back_what_they_let_go_of() {
+        let mut chain = EffectChain::default();
+        let kind = EffectKind::Delay {
+            time: 0.1,
+            feedback: 0.0,
+            mix: 0.5,
+        };
+        let effect = || BuiltEffect::new(kind, SAMPLE_RATE);
+        for i in 0..MAX_EFFECTS {
+            assert!(chain.add(EffectId(i), effect()).is_none());
+        }
+        // Replaced in place, then no room for another
+        assert!(chain.add(EffectId(0), effect()).is_some());
+        assert!(chain.add(EffectId(MAX_EFFECTS), effect()).is_some());
+        assert_eq!(chain.slots.len(), MAX_EFFECTS);
+
+        assert!(chain.remove(EffectId(1)).is_some());
+        assert!(chain.remove(EffectId(1)).is_none());
+        assert_eq!(chain.drain().count(), MAX_EFFECTS - 1);
+        assert!(chain.slots.capacity() >= MAX_EFFECTS);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use super::click::Clicker;
+use super::{Command, EffectTarget, MixerEvent, MixerMessage, Target, TrackAction, TrackEvent};
+
+/// The most tracks the mixer has room for. Every wav played gets a track of
+/// its own that is kept for the rest of the session.
+pub const MAX_TRACKS: usize = 128;
+
+/// Index of a track in the mixer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrackId(pub usize);
+
+/// Index of an effect name, shared by every chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EffectId(pub usize);
+
+/// Gives track and effect names small ids so the audio callback never
+/// handles a `String`. Ids are never reused, a wav that is loaded again
+/// plays on the track it had before.
+#[derive(Debug, Default)]
+pub struct TrackIds {
+    tracks: Vec<String>,
+    track_ids: HashMap<String, TrackId>,
+    effect_ids: HashMap<String, EffectId>,
+}
+
+impl TrackIds {
+    /// None once every track is taken
+    pub fn id(&mut self, name: &str) -> Option<TrackId> {
+        if let Some(id) = self.track_ids.get(name) {
+            return Some(*id);
+        }
+        if self.tracks.len() == MAX_TRACKS {
+            return None;
+        }
+        let id = TrackId(self.tracks.len());
+        self.tracks.push(name.to_string());
+        self.track_ids.insert(name.to_string(), id);
+        Some(id)
+    }
+
+    /// The id of a track that has already been given one
+    pub fn get(&self, name: &str) -> Option<TrackId> {
+        self.track_ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: TrackId) -> &str {
+        &self.tracks[id.0]
+    }
+
+    fn effect_id(&mut self, name: String) -> EffectId {
+        let next = EffectId(self.effect_ids.len());
+        *self.effect_ids.entry(name).or_insert(next)
+    }
+
+    /// The action with its names swapped for ids, None if it names a track
+    /// when there are none left or schedules a schedule
+    pub fn message(&mut self, action: TrackAction) -> Option<MixerMessage> {
+        match action {
+            TrackAction::Schedule(at, action) => {
+                let track = self.id(&at.track)?;
+                let command = self.command(*action)?;
+                Some(MixerMessage::Schedule(track, at.time, command))
+            }
+            action => Some(MixerMessage::Command(self.command(action)?)),
+        }
+    }
+
+    fn command(&mut self, action: TrackAction) -> Option<Command> {
+        let command = match action {
+            TrackAction::Play(track) => Command::Play(self.id(&track)?),
+            TrackAction::Stop(track) => Command::Stop(self.id(&track)?),
+            TrackAction::Reset(track) => Command::Reset(self.id(&track)?),
+            TrackAction::Seek(track, time) => Command::Seek(self.id(&track)?, time),
+            TrackAction::Rate(track, rate) => Command::Rate(self.id(&track)?, rate),
+            TrackAction::Route(track, bus) => Command::Route(self.id(&track)?, bus),
+            TrackAction::Loop(track, looping) => Command::Loop(self.id(&track)?, looping),
+            TrackAction::LoopRegion(track, region) => Command::LoopRegion(self.id(&track)?, region),
+            TrackAction::AddEffect(target, name, kind) => {
+                let target = self.target(target)?;
+                Command::AddEffect(target, self.effect_id(name), kind, [None, None])
+            }
+            TrackAction::RemoveEffect(target, name) => {
+                Command::RemoveEffect(self.target(target)?, self.effect_id(name))
+            }
+            TrackAction::AutomateEffect(target, name, automation) => {
+                let target = self.target(target)?;
+                Command::AutomateEffect(target, self.effect_id(name), automation)
+            }
+            TrackAction::Schedule(..) => return None,
+            TrackAction::ClickTrack(None) => Command::ClickTrack(None),
+            TrackAction::ClickTrack(Some(click_track)) => {
+                let track = match &click_track.track {
+                    Some(track) => Some(self.id(track)?),
+                    None => None,
+                };
+                Command::ClickTrack(Some(Clicker::new(&click_track, track)))
+            }
+            TrackAction::Position(track, position) => Command::Position(self.id(&track)?, position),
+            TrackAction::Listener(listener) => Command::Listener(listener),
+            TrackAction::Stem(track, wav) => Command::Stem(self.id(&track)?, self.id(&wav)?),
+            TrackAction::Mute(track, muted) => Command::Mute(self.id(&track)?, muted),
+            TrackAction::Tick(position) => Command::Tick(position),
+        };
+        Some(command)
+    }
+
+    fn target(&mut self, target: EffectTarget) -> Option<Target> {
+        match target {
+            EffectTarget::Track(track) => Some(Target::Track(self.id(&track)?)),
+            EffectTarget::Bus(bus) => Some(Target::Bus(bus)),
+        }
+    }
+
+    /// The event with the track named again
+    pub fn event(&self, event: MixerEvent) -> TrackEvent {
+        match event {
+            MixerEvent::Started(id) => TrackEvent::Started(self.name(id).to_string()),
+            MixerEvent::Finished(id) => TrackEvent::Finished(self.name(id).to_string()),
+            MixerEvent::Looped(id) => TrackEvent::Looped(self.name(id).to_string()),
+            MixerEvent::Underrun(id) => TrackEvent::Underrun(self.name(id).to_string()),
+            MixerEvent::Released(id) => TrackEvent::Released(self.name(id).to_string()),
+            MixerEvent::DeviceError(e) => TrackEvent::DeviceError(e),
+        }
+    }
+}
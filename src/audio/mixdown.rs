@@ -0,0 +1,383 @@
+use std::{env, error::Error, fmt::Display, fs, sync::mpsc, sync::Arc};
+
+use anyhow::{Context, Result};
+use na::Vector3;
+use tracing::{debug, info};
+
+use crate::config::SIMULATED_SAMPLE_RATE;
+use crate::resource::audio::{Wav, WavWriter};
+use crate::resource::manager::{Loadable, AUDIO_LOCATION};
+use crate::resource::vfs::Vfs;
+
+use super::analysis::AnalysisBlock;
+use super::ids::TrackIds;
+use super::mixer::Mixer;
+use super::{
+    Automation, Bus, EffectKind, EffectParam, EffectTarget, LoopRegion, MixerEvent, MixerMessage,
+    PlaybackRate, SongTime, TrackAction,
+};
+
+/// A list of timed track actions to render offline. Lines are either
+/// `load <wav>`, `end <seconds>` or `<seconds> <action> <track> [args]` where
+/// action is one of play, stop, reset, seek <seconds>, loop <true|false>,
+/// region <start> <end> <crossfade>, region off, position <x> <y> <z>,
+/// position off, stem <wav>, mute <true|false>, route <music|sfx|click> or
+/// rate <rate> <ramp> [preserve_pitch].
+/// Effects are added with `<seconds> effect <target> <name> <kind> [args]`
+/// where kind is lowpass <cutoff> <q>, highpass <cutoff> <q>,
+/// delay <time> <feedback> <mix> or reverb <room size> <damping> <mix>,
+/// moved with `<seconds> automate <target> <name> <param> <value> <ramp>` and
+/// taken out with `<seconds> remove <target> <name>`. The target is a track
+/// or `bus:<music|sfx|click>`.
+/// The listener stays at the origin facing down -z. Prefixing an action with
+/// `at <track> <seconds>` schedules it for that point in another track.
+#[derive(Debug)]
+pub struct MixdownScript {
+    pub wavs: Vec<String>,
+    pub actions: Vec<ScriptedAction>,
+    pub end: f64,
+}
+
+#[derive(Debug)]
+pub struct ScriptedAction {
+    pub time: f64,
+    pub action: TrackAction,
+}
+
+impl Loadable for MixdownScript {
+    type Output = Self;
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
+        debug!(file = file, "Loading Mixdown Script");
+        let buf = String::from_utf8(bytes)?;
+
+        let mut wavs = Vec::new();
+        let mut actions = Vec::new();
+        let mut end = None;
+        let lines = buf
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.starts_with("//") && !l.trim().is_empty());
+        for (i, line) in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "load" => wavs.push(arg(&parts, 1, i)?.to_string()),
+                "end" => end = Some(arg(&parts, 1, i)?.parse().context("Parsing end")?),
+                time => {
+                    let time: f64 = time.parse().context("Parsing action time")?;
+                    let action = parse_action(&parts, i)?;
+                    actions.push(ScriptedAction { time, action });
+                }
+            }
+        }
+        // Stable so actions at the same time keep their order in the file
+        actions.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Ok(MixdownScript {
+            wavs,
+            actions,
+            end: end.ok_or(MixdownError::MissingEnd)?,
+        })
+    }
+}
+
+fn arg<'a>(parts: &[&'a str], index: usize, line: usize) -> Result<&'a str> {
+    parts
+        .get(index)
+        .copied()
+        .ok_or(MixdownError::MissingArgument(line + 1).into())
+}
+
+fn parse_action(parts: &[&str], line: usize) -> Result<TrackAction> {
+    let track = arg(parts, 2, line)?.to_string();
+    let action = match arg(parts, 1, line)? {
+        "at" => {
+            let at = SongTime {
+                track,
+                time: arg(parts, 3, line)?.parse().context("Parsing song time")?,
+            };
+            // The song time takes the place of the action time
+            let action = parse_action(&parts[3..], line)?;
+            TrackAction::Schedule(at, Box::new(action))
+        }
+        "play" => TrackAction::Play(track),
+        "stop" => TrackAction::Stop(track),
+        "reset" => TrackAction::Reset(track),
+        "seek" => {
+            let time = arg(parts, 3, line)?.parse().context("Parsing seek")?;
+            TrackAction::Seek(track, time)
+        }
+        "region" if arg(parts, 3, line)? == "off" => TrackAction::LoopRegion(track, None),
+        "region" => {
+            let region = LoopRegion {
+                start: arg(parts, 3, line)?
+                    .parse()
+                    .context("Parsing region start")?,
+                end: arg(parts, 4, line)?.parse().context("Parsing region end")?,
+                crossfade: arg(parts, 5, line)?.parse().context("Parsing crossfade")?,
+            };
+            TrackAction::LoopRegion(track, Some(region))
+        }
+        "position" if arg(parts, 3, line)? == "off" => TrackAction::Position(track, None),
+        "position" => {
+            let mut position = Vector3::zeros();
+            for (i, axis) in position.iter_mut().enumerate() {
+                *axis = arg(parts, 3 + i, line)?
+                    .parse()
+                    .context("Parsing position")?;
+            }
+            TrackAction::Position(track, Some(position))
+        }
+        "stem" => TrackAction::Stem(track, arg(parts, 3, line)?.to_string()),
+        "mute" => {
+            let muted = arg(parts, 3, line)?.parse().context("Parsing mute")?;
+            TrackAction::Mute(track, muted)
+        }
+        "loop" => {
+            let looping = arg(parts, 3, line)?.parse().context("Parsing loop")?;
+            TrackAction::Loop(track, looping)
+        }
+        "route" => TrackAction::Route(track, parse_bus(arg(parts, 3, line)?, line)?),
+        "rate" => {
+            let rate = PlaybackRate {
+                rate: arg(parts, 3, line)?.parse().context("Parsing rate")?,
+                ramp: arg(parts, 4, line)?.parse().context("Parsing ramp")?,
+                preserve_pitch: parts.get(5) == Some(&"preserve_pitch"),
+            };
+            TrackAction::Rate(track, rate)
+        }
+        "effect" => {
+            let name = arg(parts, 3, line)?.to_string();
+            let kind = parse_effect(&parts[4..], line)?;
+            TrackAction::AddEffect(parse_target(track, line)?, name, kind)
+        }
+        "automate" => {
+            let name = arg(parts, 3, line)?.to_string();
+            let automation = Automation {
+                param: parse_param(arg(parts, 4, line)?, line)?,
+                value: arg(parts, 5, line)?.parse().context("Parsing value")?,
+                ramp: arg(parts, 6, line)?.parse().context("Parsing ramp")?,
+            };
+            TrackAction::AutomateEffect(parse_target(track, line)?, name, automation)
+        }
+        "remove" => {
+            let name = arg(parts, 3, line)?.to_string();
+            TrackAction::RemoveEffect(parse_target(track, line)?, name)
+        }
+        _ => return Err(MixdownError::UnknownAction(line + 1).into()),
+    };
+    Ok(action)
+}
+
+fn parse_bus(bus: &str, line: usize) -> Result<Bus> {
+    match bus {
+        "music" => Ok(Bus::Music),
+        "sfx" => Ok(Bus::Sfx),
+        "click" => Ok(Bus::Click),
+        _ => Err(MixdownError::UnknownBus(line + 1).into()),
+    }
+}
+
+fn parse_target(target: String, line: usize) -> Result<EffectTarget> {
+    match target.strip_prefix("bus:") {
+        Some(bus) => Ok(EffectTarget::Bus(parse_bus(bus, line)?)),
+        None => Ok(EffectTarget::Track(target)),
+    }
+}
+
+/// `parts` starts at the kind of effect
+fn parse_effect(parts: &[&str], line: usize) -> Result<EffectKind> {
+    let value = |i| -> Result<f64> { arg(parts, i, line)?.parse().context("Parsing effect") };
+    let kind = match arg(parts, 0, line)? {
+        "lowpass" => EffectKind::LowPass {
+            cutoff: value(1)?,
+            q: value(2)?,
+        },
+        "highpass" => EffectKind::HighPass {
+            cutoff: value(1)?,
+            q: value(2)?,
+        },
+        "delay" => EffectKind::Delay {
+            time: value(1)?,
+            feedback: value(2)?,
+            mix: value(3)?,
+        },
+        "reverb" => EffectKind::Reverb {
+            room_size: value(1)?,
+            damping: value(2)?,
+            mix: value(3)?,
+        },
+        _ => return Err(MixdownError::UnknownEffect(line + 1).into()),
+    };
+    Ok(kind)
+}
+
+fn parse_param(param: &str, line: usize) -> Result<EffectParam> {
+    let param = match param {
+        "cutoff" => EffectParam::Cutoff,
+        "q" => EffectParam::Q,
+        "time" => EffectParam::Time,
+        "feedback" => EffectParam::Feedback,
+        "mix" => EffectParam::Mix,
+        "room_size" => EffectParam::RoomSize,
+        "damping" => EffectParam::Damping,
+        _ => return Err(MixdownError::UnknownParam(line + 1).into()),
+    };
+    Ok(param)
+}
+
+/// Runs the mixer as fast as possible over the script and writes the result
+/// to `output`. Actions are applied at the exact frame they are timed for.
+/// Wavs are read through `vfs` the same way the game finds them.
+pub fn mixdown(script: MixdownScript, output: &str, vfs: &Vfs) -> Result<()> {
+    let sample_rate = SIMULATED_SAMPLE_RATE;
+    let (sender, receiver) = mpsc::channel::<MixerMessage>();
+    // Events and analysis aren't needed offline and anything the mixer lets
+    // go of can be freed here, sends to the dropped receivers are ignored
+    let (events, _) = mpsc::sync_channel::<MixerEvent>(0);
+    let (analysis, _) = mpsc::sync_channel::<AnalysisBlock>(0);
+    let (garbage, _) = mpsc::sync_channel(0);
+    let mut mixer = Mixer::new(receiver, events, analysis, garbage, sample_rate as f64);
+    let mut ids = TrackIds::default();
+    for wav in script.wavs {
+        let samples = load::<Wav>(&(AUDIO_LOCATION.to_string() + &wav), vfs)?;
+        let id = ids.id(&wav).ok_or(MixdownError::Unplayable)?;
+        sender
+            .send(MixerMessage::Wav(id, Arc::new(samples)))
+            .unwrap();
+    }
+
+    let mut writer = WavWriter::create(output, sample_rate, 2)?;
+    let to_frame = |time: f64| (time * sample_rate as f64).round() as usize;
+    let total_frames = to_frame(script.end);
+    let mut actions = script.actions.into_iter().peekable();
+    let mut block = vec![[0.0; 2]; MIXDOWN_BLOCK_FRAMES];
+    let mut frame = 0;
+    while frame < total_frames {
+        while let Some(scripted) = actions.next_if(|a| to_frame(a.time) <= frame) {
+            let message = ids
+                .message(scripted.action)
+                .ok_or(MixdownError::Unplayable)?;
+            sender.send(message.prepare(sample_rate as f64)).unwrap();
+        }
+        let next_action = actions
+            .peek()
+            .map(|a| to_frame(a.time))
+            .unwrap_or(total_frames);
+        let frames = MIXDOWN_BLOCK_FRAMES
+            .min(total_frames - frame)
+            .min(next_action - frame);
+        mixer.render(&mut block[..frames]);
+        writer.write_samples(block[..frames].as_flattened())?;
+        frame += frames;
+    }
+    writer.finish()?;
+    info!(output = output, frames = total_frames, "Mixdown written");
+    Ok(())
+}
+
+/// Read a file through `vfs` the way the game would. Paths outside the
+/// search paths, like ones given on the command line, are read as they are.
+pub fn load<T: Loadable>(path: &str, vfs: &Vfs) -> Result<T::Output> {
+    let bytes = vfs
+        .read(path)
+        .with_context(|| format!("Reading {}", path))?;
+    T::parse(path, bytes).with_context(|| format!("Loading {}", path))
+}
+
+/// Compare a mixdown just rendered to disk against a golden file found
+/// through `vfs`, allowing for a one bit difference from rounding
+pub fn compare(rendered: &str, golden: &str, vfs: &Vfs) -> Result<()> {
+    let rendered = Wav::load(rendered).context("Loading rendered mixdown")?;
+    let golden = load::<Wav>(golden, vfs).context("Loading golden mixdown")?;
+    if rendered.samples.len() != golden.samples.len() {
+        return Err(
+            MixdownError::LengthMismatch(rendered.samples.len(), golden.samples.len()).into(),
+        );
+    }
+    let mismatch = rendered
+        .samples
+        .iter()
+        .zip(&golden.samples)
+        .position(|(r, g)| (r - g).abs() > 1.0);
+    match mismatch {
+        Some(i) => Err(MixdownError::SampleMismatch(i).into()),
+        None => Ok(()),
+    }
+}
+
+/// Render every script in `MIXDOWN_LOCATION` and compare each against the
+/// golden wav of the same name next to it, both found through `vfs`
+pub fn check(vfs: &Vfs) -> Result<()> {
+    let scripts: Vec<String> = vfs
+        .list(MIXDOWN_LOCATION)
+        .into_iter()
+        .filter(|name| name.ends_with(".txt"))
+        .collect();
+    if scripts.is_empty() {
+        return Err(MixdownError::NoScripts.into());
+    }
+    for name in scripts {
+        let golden = format!("{}.wav", name.trim_end_matches(".txt"));
+        let file_name = golden.trim_start_matches(MIXDOWN_LOCATION);
+        let rendered = env::temp_dir().join(format!("mixdown_check_{}", file_name));
+        let rendered = rendered.to_string_lossy();
+        mixdown(load::<MixdownScript>(&name, vfs)?, &rendered, vfs)?;
+        compare(&rendered, &golden, vfs).with_context(|| format!("Checking {}", name))?;
+        let _ = fs::remove_file(rendered.as_ref());
+        info!(script = name, "Mixdown matches golden file");
+    }
+    Ok(())
+}
+
+const MIXDOWN_BLOCK_FRAMES: usize = 1024;
+const MIXDOWN_LOCATION: &str = "assets/mixdown/";
+
+#[derive(Debug)]
+enum MixdownError {
+    MissingEnd,
+    NoScripts,
+    MissingArgument(usize),
+    UnknownAction(usize),
+    UnknownBus(usize),
+    UnknownEffect(usize),
+    UnknownParam(usize),
+    Unplayable,
+    LengthMismatch(usize, usize),
+    SampleMismatch(usize),
+}
+
+impl Display for MixdownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEnd => write!(f, "Missing end time"),
+            Self::NoScripts => write!(f, "No scripts in {}", MIXDOWN_LOCATION),
+            Self::MissingArgument(l) => write!(f, "Missing argument on line {}", l),
+            Self::UnknownAction(l) => write!(f, "Unknown action on line {}", l),
+            Self::UnknownBus(l) => write!(f, "Unknown bus on line {}", l),
+            Self::UnknownEffect(l) => write!(f, "Unknown effect on line {}", l),
+            Self::UnknownParam(l) => write!(f, "Unknown effect parameter on line {}", l),
+            Self::Unplayable => write!(
+                f,
+                "More tracks than the mixer has room for or a schedule inside a schedule"
+            ),
+            Self::LengthMismatch(r, g) => {
+                write!(f, "Rendered {} samples but golden file has {}", r, g)
+            }
+            Self::SampleMismatch(i) => write!(f, "Samples differ from golden file at {}", i),
+        }
+    }
+}
+
+impl Error for MixdownError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::resource::{manager::asset_vfs, vfs::search_paths};
+
+    #[test]
+    fn mixdowns_match_golden_files() {
+        check(&asset_vfs(search_paths(Vec::new()))).unwrap();
+    }
+}
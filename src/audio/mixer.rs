@@ -0,0 +1,908 @@
+use std::f64::consts::PI;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use na::Vector3;
+
+use crate::resource::audio::Wav;
+
+use super::analysis::{Analyser, AnalysisBlock};
+use super::click::{Clicker, Tick};
+use super::effect::{BuiltEffect, EffectChain};
+use super::ids::{TrackId, MAX_TRACKS};
+use super::{
+    Bus, Command, Garbage, Listener, LoopRegion, MixerEvent, MixerMessage, PlaybackRate, Target,
+};
+
+/// Owns everything needed to produce audio so the output callback never has
+/// to wait on the main thread. Nothing is allocated or freed while rendering:
+/// every track has its place from the start, events and analysis go out over
+/// bounded channels and whatever the mixer lets go of is sent back to the
+/// output thread as `Garbage`.
+pub struct Mixer {
+    receiver: Receiver<MixerMessage>,
+    events: SyncSender<MixerEvent>,
+    analysis: SyncSender<AnalysisBlock>,
+    garbage: SyncSender<Garbage>,
+    analyser: Analyser,
+    // Indexed by `TrackId`
+    tracks: Vec<Track>,
+    // One past the highest track id used, so unused tracks aren't visited
+    used: usize,
+    // Left and right chains, each effect is added to both
+    buses: [[EffectChain; 2]; Bus::COUNT],
+    // Sorted by the time they are due, each in its own track's time
+    scheduled: Vec<Scheduled>,
+    clicker: Option<Clicker>,
+    ticks: Vec<Tick>,
+    listener: Listener,
+    sample_rate: f64,
+}
+
+struct Scheduled {
+    track: TrackId,
+    time: f64,
+    command: Command,
+}
+
+const MAX_SCHEDULED: usize = 256;
+const MAX_TICKS: usize = 32;
+
+impl Mixer {
+    pub fn new(
+        receiver: Receiver<MixerMessage>,
+        events: SyncSender<MixerEvent>,
+        analysis: SyncSender<AnalysisBlock>,
+        garbage: SyncSender<Garbage>,
+        sample_rate: f64,
+    ) -> Self {
+        Mixer {
+            receiver,
+            events,
+            analysis,
+            garbage,
+            analyser: Analyser::new(sample_rate),
+            tracks: (0..MAX_TRACKS).map(|_| Track::new()).collect(),
+            used: 0,
+            buses: Default::default(),
+            scheduled: Vec::with_capacity(MAX_SCHEDULED),
+            clicker: None,
+            ticks: Vec::with_capacity(MAX_TICKS),
+            listener: Listener::default(),
+            sample_rate,
+        }
+    }
+
+    /// Track positions are in seconds so carry over to the new rate, effects
+    /// built for any other rate are rebuilt. Only called while no stream is
+    /// rendering.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.analyser.set_sample_rate(sample_rate);
+        let track_chains = self.tracks.iter_mut().map(|t| &mut t.effects);
+        for chain in self.buses.iter_mut().flatten().chain(track_chains) {
+            chain.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Apply everything sent so far without rendering
+    pub fn receive_messages(&mut self) {
+        while let Ok(message) = self.receiver.try_recv() {
+            self.receive(message);
+        }
+    }
+
+    /// Fill a block of left, right frames
+    pub fn render(&mut self, block: &mut [[f32; 2]]) {
+        self.receive_messages();
+
+        // Positions only change between blocks
+        for track in &mut self.tracks[..self.used] {
+            track.gains = match track.position {
+                Some(position) => spatialise(&self.listener, position),
+                None => [1.0, 1.0],
+            };
+        }
+
+        let seconds_per_sample = 1.0 / self.sample_rate;
+        for frame in block.iter_mut() {
+            self.apply_scheduled();
+            let mut bus_samples = [[0.0; 2]; Bus::COUNT];
+            if let Some(clicker) = &mut self.clicker {
+                let song_time = clicker
+                    .track()
+                    .map(|track| &self.tracks[track.0])
+                    .filter(|t| t.state == TrackState::Playing && t.wav.is_some())
+                    .map(|t| t.time);
+                let sample = clicker.next_sample(song_time, seconds_per_sample);
+                for channel in &mut bus_samples[Bus::Click.index()] {
+                    *channel += sample;
+                }
+            }
+            self.ticks.retain_mut(|tick| {
+                let Some(sample) = tick.next_sample(seconds_per_sample) else {
+                    return false;
+                };
+                for (channel, gain) in bus_samples[Bus::Sfx.index()].iter_mut().zip(tick.gains) {
+                    *channel += sample * gain;
+                }
+                true
+            });
+            for (i, track) in self.tracks[..self.used].iter_mut().enumerate() {
+                if track.state != TrackState::Playing {
+                    continue;
+                }
+                let id = TrackId(i);
+                if track.wav.is_none() {
+                    if !track.underrun {
+                        track.underrun = true;
+                        let _ = self.events.try_send(MixerEvent::Underrun(id));
+                    }
+                    continue;
+                }
+                let sample = match track.next_sample() {
+                    Some(sample) => sample,
+                    None if track.looping => {
+                        track.seek(0.0);
+                        let _ = self.events.try_send(MixerEvent::Looped(id));
+                        track.next_sample().unwrap_or(0.0)
+                    }
+                    None => {
+                        track.state = TrackState::Stopped;
+                        track.time = 0.0;
+                        let _ = self.events.try_send(MixerEvent::Finished(id));
+                        continue;
+                    }
+                };
+                // A track stopped by a tape stop sits on one sample, fade it
+                // out rather than leave a DC offset running into the effects
+                track.stall.mute(track.stalled());
+                let sample = sample * track.fader.next(seconds_per_sample)
+                    + track.stems_sample(seconds_per_sample);
+                let sample = sample * track.stall.next(seconds_per_sample);
+                let sample = track.effects.process(sample / 32_768.0);
+                for (channel, gain) in bus_samples[track.bus.index()].iter_mut().zip(track.gains) {
+                    *channel += sample * gain;
+                }
+                if track.advance(seconds_per_sample) {
+                    let _ = self.events.try_send(MixerEvent::Looped(id));
+                }
+            }
+            let mut result = [0.0; 2];
+            let mut bus_levels = [0.0; Bus::COUNT];
+            for ((chains, samples), level) in
+                self.buses.iter_mut().zip(bus_samples).zip(&mut bus_levels)
+            {
+                for ((chain, sample), output) in chains.iter_mut().zip(samples).zip(&mut result) {
+                    let processed = chain.process(sample);
+                    *output += processed;
+                    *level += processed / 2.0;
+                }
+            }
+            if let Some(block) = self.analyser.push(&bus_levels) {
+                // Nothing is listening or the last blocks haven't been
+                // taken, keep it for next time
+                if let Err(TrySendError::Full(block) | TrySendError::Disconnected(block)) =
+                    self.analysis.try_send(block)
+                {
+                    self.analyser.recycle(block);
+                }
+            }
+            // music_wav_second as f32 caused varying rate so use f64 and
+            // convert to f32 at the end. If f64 precision still leads to noticeable
+            // drift on longer tracks this will need refactoring to not use
+            // floats for time calculations
+            *frame = result.map(|channel| channel as f32);
+        }
+    }
+
+    fn receive(&mut self, message: MixerMessage) {
+        match message {
+            MixerMessage::Command(command) => self.apply(command),
+            MixerMessage::Schedule(track, time, command) => self.schedule(track, time, command),
+            MixerMessage::Wav(id, wav) => {
+                let track = self.track(id);
+                let old = track.wav.replace(wav.clone());
+                track.underrun = false;
+                self.free_wav(old);
+                for i in 0..self.used {
+                    let stems = self.tracks[i].stems.iter_mut().filter(|s| s.wav_id == id);
+                    for stem in stems {
+                        if let Some(old) = stem.wav.replace(wav.clone()) {
+                            free(&self.garbage, Garbage::Wav(old));
+                        }
+                    }
+                }
+            }
+            MixerMessage::Release(id) => {
+                if self.clicker.as_ref().is_some_and(|c| c.track() == Some(id)) {
+                    self.clicker = None;
+                }
+                self.apply_all_scheduled(id);
+                let track = &mut self.tracks[id.0];
+                track.release(&self.garbage);
+                for i in 0..self.used {
+                    let stems = self.tracks[i].stems.iter_mut().filter(|s| s.wav_id == id);
+                    for stem in stems {
+                        if let Some(old) = stem.wav.take() {
+                            free(&self.garbage, Garbage::Wav(old));
+                        }
+                    }
+                }
+                let _ = self.events.try_send(MixerEvent::Released(id));
+            }
+            MixerMessage::Recycle(block) => self.analyser.recycle(block),
+            MixerMessage::Advance(_) | MixerMessage::Shutdown => (),
+        }
+    }
+
+    /// After anything already scheduled for the same time. Dropped if there
+    /// are already `MAX_SCHEDULED` waiting.
+    fn schedule(&mut self, track: TrackId, time: f64, command: Command) {
+        self.track(track);
+        if self.scheduled.len() == MAX_SCHEDULED {
+            free(&self.garbage, Garbage::Command(command));
+            return;
+        }
+        let i = self.scheduled.partition_point(|s| s.time <= time);
+        let scheduled = Scheduled {
+            track,
+            time,
+            command,
+        };
+        self.scheduled.insert(i, scheduled);
+    }
+
+    /// Checked every frame so actions land on the sample they are due.
+    /// Actions on a track that has stopped advancing, whether stopped,
+    /// finished, stalled or without its wav, are overdue and applied straight
+    /// away rather than waiting for a time the track may never reach.
+    fn apply_scheduled(&mut self) {
+        let mut i = 0;
+        while i < self.scheduled.len() {
+            let scheduled = &self.scheduled[i];
+            if !self.tracks[scheduled.track.0].reached(scheduled.time) {
+                i += 1;
+                continue;
+            }
+            let scheduled = self.scheduled.remove(i);
+            self.apply(scheduled.command);
+            // Applying it may have made earlier actions due
+            i = 0;
+        }
+    }
+
+    /// Apply every action waiting on the track, in the order they are due
+    fn apply_all_scheduled(&mut self, track: TrackId) {
+        while let Some(i) = self.scheduled.iter().position(|s| s.track == track) {
+            let scheduled = self.scheduled.remove(i);
+            self.apply(scheduled.command);
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            // Anything waiting on the old timeline goes first
+            Command::Reset(track) => {
+                self.apply_all_scheduled(track);
+                self.track(track).reset();
+            }
+            Command::Play(track) => {
+                let t = self.track(track);
+                if t.state != TrackState::Playing {
+                    t.state = TrackState::Playing;
+                    let _ = self.events.try_send(MixerEvent::Started(track));
+                }
+            }
+            Command::Rate(track, rate) => self.track(track).set_rate(rate),
+            Command::Stop(track) => self.track(track).state = TrackState::Stopped,
+            Command::Route(track, bus) => self.track(track).bus = bus,
+            Command::Seek(track, time) => self.track(track).seek(time),
+            Command::Loop(track, looping) => self.track(track).looping = looping,
+            // Checked by `Command::prepare`
+            Command::LoopRegion(track, region) => self.track(track).region = region,
+            Command::AddEffect(target, id, _, effects) => {
+                let mut chains = self.effect_chains(target).iter_mut();
+                let mut unused = [None, None];
+                for (effect, unused) in effects.into_iter().zip(&mut unused) {
+                    let Some(effect) = effect else {
+                        continue;
+                    };
+                    *unused = match chains.next() {
+                        Some(chain) => chain.add(id, effect),
+                        None => Some(effect),
+                    };
+                }
+                for effect in unused.into_iter().flatten() {
+                    free(&self.garbage, Garbage::Effect(effect));
+                }
+            }
+            Command::RemoveEffect(target, id) => {
+                let mut removed: [Option<BuiltEffect>; 2] = [None, None];
+                for (chain, removed) in self.effect_chains(target).iter_mut().zip(&mut removed) {
+                    *removed = chain.remove(id);
+                }
+                for effect in removed.into_iter().flatten() {
+                    free(&self.garbage, Garbage::Effect(effect));
+                }
+            }
+            Command::AutomateEffect(target, id, automation) => {
+                let sample_rate = self.sample_rate;
+                for chain in self.effect_chains(target) {
+                    chain.automate(id, automation, sample_rate);
+                }
+            }
+            Command::ClickTrack(clicker) => self.clicker = clicker,
+            Command::Position(track, position) => self.track(track).position = position,
+            Command::Listener(listener) => self.listener = listener,
+            Command::Stem(track, wav_id) => {
+                let wav = self.tracks[wav_id.0].wav.clone();
+                let t = self.track(track);
+                if !t.stems.iter().any(|s| s.wav_id == wav_id) && t.stems.len() < MAX_STEMS {
+                    t.stems.push(Stem::new(wav_id, wav));
+                } else if let Some(wav) = wav {
+                    free(&self.garbage, Garbage::Wav(wav));
+                }
+            }
+            Command::Mute(id, muted) => {
+                self.track(id).fader.mute(muted);
+                for track in &mut self.tracks[..self.used] {
+                    let stems = track.stems.iter_mut().filter(|s| s.wav_id == id);
+                    for stem in stems {
+                        stem.fader.mute(muted);
+                    }
+                }
+            }
+            Command::Tick(position) => {
+                if self.ticks.len() < MAX_TICKS {
+                    let gains = spatialise(&self.listener, position);
+                    self.ticks.push(Tick::new(gains));
+                }
+            }
+        }
+    }
+
+    fn track(&mut self, id: TrackId) -> &mut Track {
+        self.used = self.used.max(id.0 + 1);
+        &mut self.tracks[id.0]
+    }
+
+    fn free_wav(&self, wav: Option<Arc<Wav>>) {
+        if let Some(wav) = wav {
+            free(&self.garbage, Garbage::Wav(wav));
+        }
+    }
+
+    /// Tracks are mono until they are panned so only have one chain
+    fn effect_chains(&mut self, target: Target) -> &mut [EffectChain] {
+        match target {
+            Target::Track(track) => std::slice::from_mut(&mut self.track(track).effects),
+            Target::Bus(bus) => &mut self.buses[bus.index()],
+        }
+    }
+}
+
+/// Send something the mixer is done with to be freed on the output thread.
+/// Only dropped here if the output isn't keeping up, or has no thread of its
+/// own to free it on.
+fn free(garbage: &SyncSender<Garbage>, item: Garbage) {
+    let _ = garbage.try_send(item);
+}
+
+/// Left and right gains for a sound at `position`. Centred sounds are at full
+/// volume in both ears so non-positional tracks are unchanged, moving to one
+/// side fades out the other ear. Volume falls off with distance past
+/// `REFERENCE_DISTANCE`.
+fn spatialise(listener: &Listener, position: Vector3<f32>) -> [f64; 2] {
+    let offset = position - listener.position;
+    let distance = offset.norm();
+    let pan = if distance > 0.0 {
+        (offset.dot(&listener.right) / distance).clamp(-1.0, 1.0) as f64
+    } else {
+        0.0
+    };
+    let attenuation = (REFERENCE_DISTANCE / distance.max(REFERENCE_DISTANCE)) as f64;
+    [
+        f64::min(1.0, 1.0 - pan) * attenuation,
+        f64::min(1.0, 1.0 + pan) * attenuation,
+    ]
+}
+
+const REFERENCE_DISTANCE: f32 = 10.0;
+
+struct Track {
+    state: TrackState,
+    wav: Option<Arc<Wav>>,
+    // Only report a missing wav once per play
+    underrun: bool,
+    looping: bool,
+    region: Option<LoopRegion>,
+    time: f64,
+    bus: Bus,
+    position: Option<Vector3<f32>>,
+    gains: [f64; 2],
+    fader: Fader,
+    // Faded out while the rate is held at 0
+    stall: Fader,
+    stems: Vec<Stem>,
+    effects: EffectChain,
+    rate: f64,
+    target_rate: f64,
+    // Change in rate per second of output
+    rate_step: f64,
+    preserve_pitch: bool,
+    grains: [Grain; 2],
+}
+
+const MAX_STEMS: usize = 8;
+
+impl Track {
+    fn new() -> Self {
+        Track {
+            state: TrackState::Stopped,
+            wav: None,
+            underrun: false,
+            looping: false,
+            region: None,
+            time: 0.0,
+            bus: Bus::Music,
+            position: None,
+            gains: [1.0, 1.0],
+            fader: Fader::default(),
+            stall: Fader::default(),
+            stems: Vec::with_capacity(MAX_STEMS),
+            effects: EffectChain::default(),
+            rate: 1.0,
+            target_rate: 1.0,
+            rate_step: 0.0,
+            preserve_pitch: false,
+            grains: Grain::start_at(0.0),
+        }
+    }
+
+    /// Back to the start, stopped and unmuted. Routing, stems and effects are
+    /// kept so they don't need to be set again.
+    fn reset(&mut self) {
+        self.state = TrackState::Stopped;
+        self.fader = Fader::default();
+        for stem in &mut self.stems {
+            stem.fader = Fader::default();
+        }
+        self.underrun = false;
+        self.time = 0.0;
+        self.stall = Fader::default();
+        self.rate = 1.0;
+        self.target_rate = 1.0;
+        self.rate_step = 0.0;
+        self.preserve_pitch = false;
+        self.grains = Grain::start_at(0.0);
+    }
+
+    /// Back to how it was before it was first used, with everything it held
+    /// sent to be freed
+    fn release(&mut self, garbage: &SyncSender<Garbage>) {
+        self.reset();
+        if let Some(wav) = self.wav.take() {
+            free(garbage, Garbage::Wav(wav));
+        }
+        for stem in self.stems.drain(..) {
+            if let Some(wav) = stem.wav {
+                free(garbage, Garbage::Wav(wav));
+            }
+        }
+        for effect in self.effects.drain() {
+            free(garbage, Garbage::Effect(effect));
+        }
+        self.looping = false;
+        self.region = None;
+        self.bus = Bus::Music;
+        self.position = None;
+    }
+
+    fn set_rate(&mut self, rate: PlaybackRate) {
+        self.target_rate = rate.rate;
+        if rate.ramp > 0.0 {
+            self.rate_step = (rate.rate - self.rate).abs() / rate.ramp;
+        } else {
+            self.rate = rate.rate;
+        }
+        if rate.preserve_pitch && !self.preserve_pitch {
+            self.grains = Grain::start_at(self.time);
+        }
+        self.preserve_pitch = rate.preserve_pitch;
+    }
+
+    /// Whether an action due at `time` should be applied now
+    fn reached(&self, time: f64) -> bool {
+        let advancing = self.state == TrackState::Playing && self.wav.is_some() && !self.stalled();
+        self.time >= time || !advancing
+    }
+
+    /// Stopped by slowing down to nothing rather than by `Stop`
+    fn stalled(&self) -> bool {
+        self.rate == 0.0 && self.target_rate == 0.0
+    }
+
+    fn seek(&mut self, time: f64) {
+        self.time = time;
+        self.grains = Grain::start_at(time);
+    }
+
+    /// None once the track has run out of samples
+    fn next_sample(&self) -> Option<f64> {
+        self.next_sample_of(self.wav.as_deref()?)
+    }
+
+    /// Read `wav` at the track's position, crossfading into the start of the
+    /// loop region the same way whichever of the track's wavs it is
+    fn next_sample_of(&self, wav: &Wav) -> Option<f64> {
+        let sample = self.sample_at(wav, 0.0)?;
+        let Some(region) = self.region else {
+            return Some(sample);
+        };
+        let fade_start = region.end - region.crossfade;
+        if self.time < fade_start || self.time >= region.end {
+            return Some(sample);
+        }
+        // Fade in the audio leading up to the region start so the jump back
+        // at the end of the region lands where the faded in audio left off
+        let fade = (self.time - fade_start) / region.crossfade;
+        let wrapped = self
+            .sample_at(wav, region.end - region.start)
+            .unwrap_or(0.0);
+        Some(sample * (1.0 - fade) + wrapped * fade)
+    }
+
+    /// The stems read at the track's position, stems that are shorter than
+    /// the track fall silent. Called before the track advances.
+    fn stems_sample(&mut self, seconds: f64) -> f64 {
+        let mut result = 0.0;
+        // Taken so each stem can be read with the track's position
+        let mut stems = std::mem::take(&mut self.stems);
+        for stem in &mut stems {
+            let gain = stem.fader.next(seconds);
+            let Some(wav) = stem.wav.as_deref() else {
+                continue;
+            };
+            if gain > 0.0 {
+                result += self.next_sample_of(wav).unwrap_or(0.0) * gain;
+            }
+        }
+        self.stems = stems;
+        result
+    }
+
+    /// Read the track `offset` seconds behind where it is now
+    fn sample_at(&self, wav: &Wav, offset: f64) -> Option<f64> {
+        let sample = interpolate(wav, self.time - offset)?;
+        if !self.preserve_pitch {
+            return Some(sample);
+        }
+        // Overlap-add two grains read at normal speed. The windows of grains
+        // half a grain apart sum to 1 so the output level stays the same.
+        let mut result = 0.0;
+        for grain in &self.grains {
+            let window = (PI * grain.phase / GRAIN_SECONDS).sin().powi(2);
+            let grain_sample = interpolate(wav, grain.start + grain.phase - offset).unwrap_or(0.0);
+            result += window * grain_sample;
+        }
+        Some(result)
+    }
+
+    /// True if the track jumped back to the start of its loop region
+    fn advance(&mut self, seconds: f64) -> bool {
+        if self.rate < self.target_rate {
+            self.rate = f64::min(self.rate + self.rate_step * seconds, self.target_rate);
+        } else if self.rate > self.target_rate {
+            self.rate = f64::max(self.rate - self.rate_step * seconds, self.target_rate);
+        }
+        let previous = self.time;
+        self.time += seconds * self.rate;
+        for grain in &mut self.grains {
+            grain.phase += seconds;
+            if grain.phase >= GRAIN_SECONDS {
+                grain.phase -= GRAIN_SECONDS;
+                grain.start = self.time;
+            }
+        }
+
+        // Only wrap when crossing the end so seeking past a region escapes it
+        let Some(region) = self.region else {
+            return false;
+        };
+        if previous >= region.end || self.time < region.end {
+            return false;
+        }
+        let length = region.end - region.start;
+        self.time -= length;
+        for grain in &mut self.grains {
+            grain.start -= length;
+        }
+        true
+    }
+}
+
+/// Another wav played at the position of the track that owns it
+struct Stem {
+    // The track the wav is loaded into
+    wav_id: TrackId,
+    wav: Option<Arc<Wav>>,
+    fader: Fader,
+}
+
+impl Stem {
+    fn new(wav_id: TrackId, wav: Option<Arc<Wav>>) -> Self {
+        Stem {
+            wav_id,
+            wav,
+            fader: Fader::default(),
+        }
+    }
+}
+
+/// Ramps muting in and out over `MUTE_FADE_SECONDS` so it doesn't click
+#[derive(Debug, Clone, Copy)]
+struct Fader {
+    gain: f64,
+    muted: bool,
+}
+
+impl Default for Fader {
+    fn default() -> Self {
+        Fader {
+            gain: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl Fader {
+    fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// The gain for this sample
+    fn next(&mut self, seconds: f64) -> f64 {
+        let gain = self.gain;
+        let step = seconds / MUTE_FADE_SECONDS;
+        self.gain = if self.muted {
+            f64::max(gain - step, 0.0)
+        } else {
+            f64::min(gain + step, 1.0)
+        };
+        gain
+    }
+}
+
+const MUTE_FADE_SECONDS: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy)]
+struct Grain {
+    start: f64,
+    phase: f64,
+}
+
+impl Grain {
+    fn start_at(time: f64) -> [Grain; 2] {
+        [
+            Grain {
+                start: time,
+                phase: 0.0,
+            },
+            Grain {
+                start: time - GRAIN_SECONDS / 2.0,
+                phase: GRAIN_SECONDS / 2.0,
+            },
+        ]
+    }
+}
+
+const GRAIN_SECONDS: f64 = 0.08;
+
+fn interpolate(wav: &Wav, time: f64) -> Option<f64> {
+    if time < 0.0 {
+        return Some(0.0);
+    }
+    let samples = &wav.samples;
+    let raw_index = time * wav.sample_rate as f64;
+    let floor_index = raw_index.floor();
+    let ceil_index = raw_index.ceil();
+    if ceil_index as usize >= samples.len() {
+        return None;
+    }
+    let floor_sample = samples[floor_index as usize];
+    let ceil_sample = samples[ceil_index as usize];
+    let lambda = raw_index - floor_index;
+    Some(lambda * ceil_sample + ((1.0 - lambda) * floor_sample))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrackState {
+    Playing,
+    Stopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 1_000.0;
+    const MUSIC: TrackId = TrackId(0);
+    const SFX: TrackId = TrackId(1);
+
+    /// A mixer with a second of silence loaded into `MUSIC` and `SFX`
+    fn mixer() -> (Sender<MixerMessage>, Mixer) {
+        let (sender, receiver) = mpsc::channel();
+        let (events, _) = mpsc::sync_channel(0);
+        let (analysis, _) = mpsc::sync_channel(0);
+        let (garbage, _) = mpsc::sync_channel(0);
+        let mixer = Mixer::new(receiver, events, analysis, garbage, SAMPLE_RATE);
+        let wav = Arc::new(Wav {
+            samples: vec![0.0; SAMPLE_RATE as usize],
+            sample_rate: SAMPLE_RATE as u32,
+        });
+        for track in [MUSIC, SFX] {
+            sender.send(MixerMessage::Wav(track, wav.clone())).unwrap();
+        }
+        (sender, mixer)
+    }
+
+    fn send(sender: &Sender<MixerMessage>, command: Command) {
+        let message = MixerMessage::Command(command).prepare(SAMPLE_RATE);
+        sender.send(message).unwrap();
+    }
+
+    /// Play `SFX` when `MUSIC` reaches `time`
+    fn schedule_sfx(sender: &Sender<MixerMessage>, time: f64) {
+        let message = MixerMessage::Schedule(MUSIC, time, Command::Play(SFX));
+        sender.send(message).unwrap();
+    }
+
+    fn render(mixer: &mut Mixer, seconds: f64) {
+        let mut block = vec![[0.0; 2]; (seconds * SAMPLE_RATE) as usize];
+        mixer.render(&mut block);
+    }
+
+    fn playing(mixer: &Mixer, track: TrackId) -> bool {
+        mixer.tracks[track.0].state == TrackState::Playing
+    }
+
+    fn time(mixer: &Mixer, track: TrackId) -> f64 {
+        mixer.tracks[track.0].time
+    }
+
+    fn region(start: f64, end: f64, crossfade: f64) -> Command {
+        let region = LoopRegion {
+            start,
+            end,
+            crossfade,
+        };
+        Command::LoopRegion(MUSIC, Some(region))
+    }
+
+    #[test]
+    fn scheduled_actions_wait_for_their_song_time() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, Command::Play(MUSIC));
+        schedule_sfx(&sender, 0.5);
+        render(&mut mixer, 0.4);
+        assert!(!playing(&mixer, SFX));
+        render(&mut mixer, 0.2);
+        assert!(playing(&mixer, SFX));
+    }
+
+    #[test]
+    fn actions_on_a_stopped_track_are_applied_straight_away() {
+        let (sender, mut mixer) = mixer();
+        schedule_sfx(&sender, 0.5);
+        render(&mut mixer, 0.001);
+        assert!(playing(&mixer, SFX));
+    }
+
+    #[test]
+    fn actions_past_the_end_are_applied_when_the_track_finishes() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, Command::Play(MUSIC));
+        schedule_sfx(&sender, 5.0);
+        render(&mut mixer, 0.9);
+        assert!(!playing(&mixer, SFX));
+        render(&mut mixer, 0.2);
+        assert!(!playing(&mixer, MUSIC));
+        assert!(playing(&mixer, SFX));
+    }
+
+    #[test]
+    fn actions_are_applied_once_a_tape_stop_stalls_the_track() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, Command::Play(MUSIC));
+        let tape_stop = PlaybackRate {
+            rate: 0.0,
+            ramp: 0.2,
+            preserve_pitch: false,
+        };
+        send(&sender, Command::Rate(MUSIC, tape_stop));
+        schedule_sfx(&sender, 0.5);
+        render(&mut mixer, 0.1);
+        assert!(!playing(&mixer, SFX));
+        render(&mut mixer, 0.2);
+        assert!(mixer.tracks[MUSIC.0].time < 0.5);
+        assert!(playing(&mixer, SFX));
+    }
+
+    #[test]
+    fn actions_are_applied_before_their_track_is_reset_or_released() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, Command::Play(MUSIC));
+        schedule_sfx(&sender, 0.9);
+        let seek = MixerMessage::Schedule(MUSIC, 0.9, Command::Seek(MUSIC, 0.5));
+        sender.send(seek).unwrap();
+        send(&sender, Command::Reset(MUSIC));
+        render(&mut mixer, 0.001);
+        assert!(playing(&mixer, SFX));
+        // The seek was on the timeline the reset replaced
+        assert!(!playing(&mixer, MUSIC));
+        assert_eq!(mixer.tracks[MUSIC.0].time, 0.0);
+        assert!(mixer.scheduled.is_empty());
+
+        send(&sender, Command::Stop(SFX));
+        send(&sender, Command::Play(MUSIC));
+        schedule_sfx(&sender, 0.9);
+        sender.send(MixerMessage::Release(MUSIC)).unwrap();
+        render(&mut mixer, 0.001);
+        assert!(playing(&mixer, SFX));
+        assert!(mixer.scheduled.is_empty());
+    }
+
+    #[test]
+    fn reset_unmutes_the_track_and_its_stems() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, Command::Stem(MUSIC, SFX));
+        send(&sender, Command::Mute(MUSIC, true));
+        send(&sender, Command::Mute(SFX, true));
+        send(&sender, Command::Play(MUSIC));
+        render(&mut mixer, 0.1);
+        send(&sender, Command::Reset(MUSIC));
+        mixer.receive_messages();
+        let music = &mixer.tracks[MUSIC.0];
+        assert!(!music.fader.muted && music.fader.gain == 1.0);
+        assert!(!music.stems[0].fader.muted && music.stems[0].fader.gain == 1.0);
+    }
+
+    #[test]
+    fn seeking_past_a_region_escapes_it() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, region(0.2, 0.4, 0.05));
+        send(&sender, Command::Play(MUSIC));
+        render(&mut mixer, 0.5);
+        assert!((0.2..0.4).contains(&time(&mixer, MUSIC)));
+        send(&sender, Command::Seek(MUSIC, 0.6));
+        render(&mut mixer, 0.2);
+        assert!((time(&mixer, MUSIC) - 0.8).abs() < 0.01);
+    }
+
+    #[test]
+    fn turning_a_region_off_mid_crossfade_plays_on() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, region(0.2, 0.4, 0.1));
+        send(&sender, Command::Play(MUSIC));
+        render(&mut mixer, 0.35);
+        send(&sender, Command::LoopRegion(MUSIC, None));
+        render(&mut mixer, 0.1);
+        assert!((time(&mixer, MUSIC) - 0.45).abs() < 0.01);
+    }
+
+    #[test]
+    fn regions_are_checked_before_they_reach_the_mixer() {
+        let (sender, mut mixer) = mixer();
+        send(&sender, region(0.2, 0.4, 1.0));
+        mixer.receive_messages();
+        let crossfade = mixer.tracks[MUSIC.0].region.map(|r| r.crossfade);
+        assert_eq!(crossfade, Some(0.4 - 0.2));
+
+        send(&sender, region(0.4, 0.2, 0.1));
+        mixer.receive_messages();
+        assert_eq!(mixer.tracks[MUSIC.0].region, None);
+    }
+}
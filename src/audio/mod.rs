@@ -1,137 +1,310 @@
-use std::collections::{HashMap, HashSet};
+mod analysis;
+mod backend;
+mod click;
+mod effect;
+mod ids;
+pub mod mixdown;
+mod mixer;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::{mpsc, Arc};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use anyhow::Result;
-use cpal::SupportedStreamConfigRange;
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, FromSample, Sample, SizedSample, StreamConfig, SupportedStreamConfig,
-};
+use na::Vector3;
 
 use tracing::{debug, error, warn};
 
+use crate::resource::cache::{Handle, LoadState};
 use crate::resource::manager::ResourceManager;
+use crate::resource::progress::{LoadRequest, LoadStage};
+use crate::resource::scope::ScopeStack;
+pub use analysis::Analysis;
+use analysis::{AnalysisBlock, SpectrumAnalyser};
+use backend::create_output;
+pub use backend::{output_devices, OutputKind};
+use click::Clicker;
+use effect::BuiltEffect;
+pub use effect::{Automation, EffectKind, EffectParam};
+use ids::{EffectId, TrackId, TrackIds};
 
 use super::resource::audio::Wav;
 
 #[derive(Debug)]
 pub struct AudioManager {
-    mixer_sender: Sender<TrackAction>,
-    wavs: Arc<RwLock<HashMap<String, Wav>>>,
+    mixer_sender: Sender<MixerMessage>,
+    ids: TrackIds,
+    // Held so the samples are shared with anything else that loads them
+    wavs: HashMap<String, Handle<Wav>>,
+    // How many times each wav has been loaded without being unloaded
+    refs: HashMap<String, usize>,
+    scopes: ScopeStack,
     resource_manager: Arc<ResourceManager>,
-    resource_rec: Receiver<(String, Result<Wav>)>,
-    resource_send: Sender<(String, Result<Wav>)>,
     message_rec: Receiver<AudioMessage>,
-    loading_files: HashSet<String>,
-    loaded_files: HashSet<String>,
+    event_rec: Receiver<MixerEvent>,
+    events: Vec<TrackEvent>,
+    analysis_rec: Receiver<AnalysisBlock>,
+    spectrum: SpectrumAnalyser,
+    analysis: Option<Analysis>,
+    loading: HashMap<String, Handle<Wav>>,
+    // The request each loading wav is reported to, if it was loaded for one
+    requests: HashMap<String, LoadRequest>,
+    // Actions for tracks whose wav is still loading, applied in order once
+    // it arrives so playback starts from the beginning of the track
+    pending: HashMap<String, Vec<TrackAction>>,
+    failed: HashMap<String, Arc<anyhow::Error>>,
+    // The output only renders as far as it is advanced
+    stepped: bool,
     audio_thread: Option<JoinHandle<()>>,
 }
 
 impl AudioManager {
-    pub fn new(resource_manager: Arc<ResourceManager>, message_rec: Receiver<AudioMessage>) -> Self {
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let device_name = device.name().unwrap();
-
-        let supported_config: Vec<SupportedStreamConfigRange> = device
-            .supported_output_configs()
-            .unwrap()
-            .into_iter()
-            .collect();
-        let config = device.default_output_config().unwrap();
-        debug!(
-            device = device_name,
-            configs = format!("{:?}", &supported_config),
-            config = format!("{:?}", &config),
-            "Output device"
-        );
-        let (sender, receiver) = mpsc::channel::<TrackAction>();
-        let wavs = Arc::new(RwLock::new(HashMap::new()));
-        let audio_thread_wavs = wavs.clone();
+    pub fn new(
+        resource_manager: Arc<ResourceManager>,
+        message_rec: Receiver<AudioMessage>,
+        output: OutputKind,
+    ) -> Self {
+        let stepped = matches!(output, OutputKind::Stepped(_));
+        let mut output = create_output(output);
+        let (sender, receiver) = mpsc::channel::<MixerMessage>();
+        // Bounded so the mixer's sends never allocate
+        let (event_send, event_rec) = mpsc::sync_channel::<MixerEvent>(EVENT_QUEUE_LENGTH);
+        let (analysis_send, analysis_rec) = mpsc::sync_channel::<AnalysisBlock>(ANALYSIS_QUEUE_LENGTH);
 
         let audio_thread = Some(thread::spawn(move || {
-            let mut audio_thread = Mixer::new(receiver, device, config.into(), audio_thread_wavs);
-            audio_thread.run();
+            output.run(receiver, event_send, analysis_send);
         }));
 
-        let (resource_send, resource_rec) = mpsc::channel::<(String, Result<Wav>)>();
-        let loading_files = std::collections::HashSet::new();
-        let loaded_files = std::collections::HashSet::new();
-
         AudioManager {
             mixer_sender: sender,
-            wavs,
+            ids: TrackIds::default(),
+            wavs: HashMap::new(),
+            refs: HashMap::new(),
+            scopes: ScopeStack::new(),
             resource_manager,
-            resource_rec,
-            resource_send,
-            loading_files,
-            loaded_files,
+            loading: HashMap::new(),
+            requests: HashMap::new(),
+            pending: HashMap::new(),
+            failed: HashMap::new(),
+            stepped,
             audio_thread,
             message_rec,
+            event_rec,
+            events: Vec::new(),
+            analysis_rec,
+            spectrum: SpectrumAnalyser::new(),
+            analysis: None,
         }
     }
 
     pub fn update(&mut self) {
+        // Events from the mixer are kept until the next update
+        self.events.clear();
+        while let Ok(event) = self.event_rec.try_recv() {
+            let event = self.ids.event(event);
+            debug!(event = format!("{:?}", event), "Track event");
+            if let TrackEvent::Released(wav) = &event {
+                self.released(wav);
+            }
+            self.events.push(event);
+        }
+
+        // Only the latest block is worth analysing, every block goes back to
+        // the mixer to be reused
+        let mut latest = None;
+        while let Ok(block) = self.analysis_rec.try_recv() {
+            if let Some(skipped) = latest.replace(block) {
+                self.mixer_sender
+                    .send(MixerMessage::Recycle(skipped))
+                    .unwrap();
+            }
+        }
+        if let Some(block) = latest {
+            self.analysis = Some(self.spectrum.analyse(&block));
+            self.mixer_sender
+                .send(MixerMessage::Recycle(block))
+                .unwrap();
+        }
+
         // Check for new messages
         while let Ok(message) = self.message_rec.try_recv() {
             match message {
-                AudioMessage::Load(s) => self.load_wav(&s),
-                AudioMessage::TrackAction(ta) => self.mixer_sender.send(ta).unwrap(),
+                AudioMessage::Load(s, request) => {
+                    self.scopes.add(&s);
+                    self.load_wav(&s, request);
+                }
+                AudioMessage::Unload(s) => {
+                    self.scopes.remove(&s);
+                    self.unload_wav(&s);
+                }
+                AudioMessage::OpenScope => self.scopes.open(),
+                AudioMessage::CloseScope => self.close_scope(),
+                AudioMessage::TrackAction(ta) => self.track_action(ta),
             }
         }
 
         // Check for loading files
-        if !self.loading_files.is_empty() {
-            let mut new_wavs = Vec::with_capacity(self.loading_files.len());
-            while let Ok((file, res)) = self.resource_rec.try_recv() {
-                debug!(file = file, "Wav loaded Rec");
-                self.loading_files.remove(&file);
-                match res {
-                    Ok(w) => {
-                        new_wavs.push((file, w));
-                    }
-                    Err(e) => error!(err = e.to_string(), "Failed to load wav"),
+        if !self.loading.is_empty() {
+            // Finished handles are moved out, the rest keep loading
+            self.loading.retain(|file, handle| {
+                let state = handle.state();
+                if let LoadState::Pending = state {
+                    return true;
                 }
-            }
-            if new_wavs.len() != 0 {
-                let mut wavs_lock = self.wavs.write().unwrap();
-                for (f, w) in new_wavs {
-                    wavs_lock.insert(f.clone(), w);
-                    self.loaded_files.insert(f);
+                debug!(file = file, "Wav loaded Rec");
+                let pending = self.pending.remove(file).unwrap_or_default();
+                if let Some(request) = self.requests.remove(file) {
+                    let stage = match state {
+                        LoadState::Ready(_) => LoadStage::Done,
+                        _ => LoadStage::Failed,
+                    };
+                    request.set_stage(file, stage);
                 }
-                if self.loading_files.is_empty() {
-                    let keys: Vec<&String> = wavs_lock.keys().collect();
-                    debug!(wavs = format!("{:?}", keys), "Loaded All Wavs");
+                match state {
+                    LoadState::Ready(wav) => {
+                        send_wav(&self.mixer_sender, &mut self.ids, file, wav);
+                        self.wavs.insert(file.clone(), handle.clone());
+                        for action in pending {
+                            send_action(&self.mixer_sender, &mut self.ids, action);
+                        }
+                    }
+                    LoadState::Failed(e) => {
+                        error!(
+                            err = e.to_string(),
+                            file = file,
+                            dropped_actions = pending.len(),
+                            "Failed to load wav"
+                        );
+                        self.failed.insert(file.clone(), e);
+                    }
+                    LoadState::Pending => (),
                 }
+                false
+            });
+            if self.loading.is_empty() {
+                let keys: Vec<&String> = self.wavs.keys().collect();
+                debug!(wavs = format!("{:?}", keys), "Loaded All Wavs");
             }
         }
     }
 
-    fn load_wav(&mut self, wav: &str) {
+    fn track_action(&mut self, action: TrackAction) {
+        let Some(track) = action.track() else {
+            send_action(&self.mixer_sender, &mut self.ids, action);
+            return;
+        };
+        if self.loading.contains_key(track) {
+            let track = track.to_string();
+            self.pending.entry(track).or_default().push(action);
+        } else if self.failed.contains_key(track) {
+            warn!(track = track, "Dropping action for wav that failed to load");
+        } else {
+            send_action(&self.mixer_sender, &mut self.ids, action);
+        }
+    }
+
+    fn load_wav(&mut self, wav: &str, request: Option<LoadRequest>) {
         debug!("Load Wavs");
-        if self.loaded_files.contains(wav) {
+        *self.refs.entry(wav.to_string()).or_insert(0) += 1;
+        // Try again in case the file has been fixed
+        self.failed.remove(wav);
+        if self.wavs.contains_key(wav) {
+            if let Some(request) = request {
+                request.set_stage(wav, LoadStage::Done);
+            }
+            return;
+        }
+        if let Some(request) = &request {
+            self.requests.insert(wav.to_string(), request.clone());
+        }
+        if self.loading.contains_key(wav) {
+            return;
+        }
+        let handle = match &request {
+            Some(request) => self.resource_manager.handle_for::<Wav>(request, wav),
+            None => self.resource_manager.handle::<Wav>(wav),
+        };
+        self.loading.insert(wav.to_string(), handle);
+    }
+
+    /// The samples are kept until the mixer confirms it has let go of them,
+    /// so they are never freed on the audio thread.
+    fn unload_wav(&mut self, wav: &str) {
+        let Some(count) = self.refs.get_mut(wav) else {
+            warn!(wav = wav, "Unloading wav that isn't loaded");
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.refs.remove(wav);
+        self.failed.remove(wav);
+        // Still loading, the load is dropped along with the handle
+        self.loading.remove(wav);
+        self.requests.remove(wav);
+        self.pending.remove(wav);
+        if let (true, Some(id)) = (self.wavs.contains_key(wav), self.ids.get(wav)) {
+            self.mixer_sender.send(MixerMessage::Release(id)).unwrap();
+        }
+    }
+
+    /// Unload everything loaded in the scope that is still outstanding
+    fn close_scope(&mut self) {
+        let Some(scope) = self.scopes.close() else {
+            warn!("Closing the global audio scope");
             return;
+        };
+        for (wav, count) in scope {
+            debug!(wav = wav, loads = count, "Releasing scoped wav");
+            for _ in 0..count {
+                self.unload_wav(&wav);
+            }
         }
-        self.resource_manager
-            .load_wav(wav.to_string(), self.resource_send.clone());
-        self.loading_files.insert(wav.to_string());
     }
 
-    //pub fn unload_wav(&mut self, wav: &str) {
-    //    self.mixer_sender.send(TrackAction::Cleanup(wav.to_string())).unwrap();
-    //    self.loaded_files.remove(wav);
-    //    // This may happen before the track is cleaned up
-    //    self.wavs.write().unwrap().remove(wav);
-    //}
+    fn released(&mut self, wav: &str) {
+        // Loaded again before the mixer got the release
+        if self.refs.contains_key(wav) {
+            if let Some(samples) = self.wavs.get(wav).and_then(Handle::get) {
+                send_wav(&self.mixer_sender, &mut self.ids, wav, samples);
+            }
+            return;
+        }
+        if let Some(handle) = self.wavs.remove(wav) {
+            // Other holders of the handle keep the samples alive
+            debug!(wav = wav, shared = handle.ref_count() - 1, "Wav unloaded");
+        }
+    }
 
-    pub fn loaded_check(&self) -> (usize, usize) {
-        (self.loading_files.len(), self.loaded_files.len())
+    /// Move a stepped output on by the time since the last update, other
+    /// outputs keep time themselves
+    pub fn advance(&self, delta_time: Duration) {
+        if self.stepped {
+            self.mixer_sender
+                .send(MixerMessage::Advance(delta_time))
+                .unwrap();
+        }
+    }
+
+    pub fn events(&self) -> &[TrackEvent] {
+        &self.events
+    }
+
+    /// Levels of what was most recently played, updated `ANALYSIS_RATE`
+    /// times a second
+    pub fn analysis(&self) -> Option<&Analysis> {
+        self.analysis.as_ref()
+    }
+
+    pub fn load_error(&self, wav: &str) -> Option<&anyhow::Error> {
+        self.failed.get(wav).map(|e| e.as_ref())
     }
 
     pub fn cleanup(&mut self) {
-        self.mixer_sender.send(TrackAction::ShutdownThread).unwrap();
+        self.mixer_sender.send(MixerMessage::Shutdown).unwrap();
         if let Some(thread) = self.audio_thread.take() {
             thread.join().unwrap();
         } else {
@@ -140,224 +313,327 @@ impl AudioManager {
     }
 }
 
-struct Mixer {
-    device: Device,
-    config: SupportedStreamConfig,
-    receiver: Receiver<TrackAction>,
-    wavs: Arc<RwLock<HashMap<String, Wav>>>,
+/// Warns instead of sending if every track is taken
+fn send_wav(sender: &Sender<MixerMessage>, ids: &mut TrackIds, wav: &str, samples: Arc<Wav>) {
+    match ids.id(wav) {
+        Some(id) => sender.send(MixerMessage::Wav(id, samples)).unwrap(),
+        None => warn!(wav = wav, "No tracks left for wav"),
+    }
 }
 
-impl Mixer {
-    fn new(
-        receiver: Receiver<TrackAction>,
-        device: Device,
-        config: SupportedStreamConfig,
-        wavs: Arc<RwLock<HashMap<String, Wav>>>,
-    ) -> Self {
-        Mixer {
-            device,
-            config,
-            receiver,
-            wavs,
-        }
+fn send_action(sender: &Sender<MixerMessage>, ids: &mut TrackIds, action: TrackAction) {
+    match ids.message(action) {
+        Some(message) => sender.send(message).unwrap(),
+        None => warn!("Dropping action that schedules a schedule or needs a new track when none are left"),
     }
+}
 
-    fn run(&mut self) {
-        match self.config.sample_format() {
-            cpal::SampleFormat::I8 => self.play::<i8>(),
-            cpal::SampleFormat::I16 => self.play::<i16>(),
-            cpal::SampleFormat::I32 => self.play::<i32>(),
-            cpal::SampleFormat::I64 => self.play::<i64>(),
-            cpal::SampleFormat::U8 => self.play::<u8>(),
-            cpal::SampleFormat::U16 => self.play::<u16>(),
-            cpal::SampleFormat::U32 => self.play::<u32>(),
-            cpal::SampleFormat::U64 => self.play::<u64>(),
-            cpal::SampleFormat::F32 => self.play::<f32>(),
-            cpal::SampleFormat::F64 => self.play::<f64>(),
-            sample_format => panic!("Unsupported sample format '{sample_format}'"),
-        };
+#[derive(Debug)]
+pub enum AudioMessage {
+    /// Each load must be matched by an unload, or its scope closing, before
+    /// the wav is freed. Its progress is reported to the request if given.
+    Load(String, Option<LoadRequest>),
+    Unload(String),
+    /// Loads from now on are unloaded when the scope is closed
+    OpenScope,
+    CloseScope,
+    TrackAction(TrackAction),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TrackAction {
+    Play(String),
+    Stop(String),
+    Reset(String),
+    Seek(String, f64),
+    Rate(String, PlaybackRate),
+    Route(String, Bus),
+    /// Start again from the beginning when the end of the track is reached
+    Loop(String, bool),
+    /// Repeat part of the track, None goes back to playing straight through
+    LoopRegion(String, Option<LoopRegion>),
+    AddEffect(EffectTarget, String, EffectKind),
+    RemoveEffect(EffectTarget, String),
+    AutomateEffect(EffectTarget, String, Automation),
+    /// Apply the action on the first sample at or after the given song time.
+    /// If the song's track stops advancing first, by stopping, finishing,
+    /// stalling or losing its wav, the action is applied straight away.
+    Schedule(SongTime, Box<TrackAction>),
+    /// Replace the metronome on the click bus, None turns it off
+    ClickTrack(Option<ClickTrack>),
+    /// Pan and attenuate the track relative to the listener, None plays it
+    /// centred at full volume
+    Position(String, Option<Vector3<f32>>),
+    Listener(Listener),
+    /// Play a second wav inside the track so it stays sample locked, sharing
+    /// the track's position, rate, routing and effects
+    Stem(String, String),
+    /// Fade a track or stem out, or back in. Stems are named by their wav.
+    Mute(String, bool),
+    /// A short synthesised tick on the sfx bus heard from a world position
+    Tick(Vector3<f32>),
+}
+
+impl TrackAction {
+    /// The track the action applies to, bus actions have none
+    fn track(&self) -> Option<&str> {
+        match self {
+            Self::Play(track)
+            | Self::Stop(track)
+            | Self::Reset(track)
+            | Self::Seek(track, _)
+            | Self::Rate(track, _)
+            | Self::Route(track, _)
+            | Self::Loop(track, _)
+            | Self::LoopRegion(track, _)
+            | Self::Position(track, _)
+            | Self::Stem(track, _)
+            | Self::Mute(track, _) => Some(track),
+            Self::AddEffect(target, _, _)
+            | Self::RemoveEffect(target, _)
+            | Self::AutomateEffect(target, _, _) => match target {
+                EffectTarget::Track(track) => Some(track),
+                EffectTarget::Bus(_) => None,
+            },
+            Self::Schedule(_, action) => action.track(),
+            Self::ClickTrack(click_track) => click_track.as_ref().and_then(|c| c.track.as_deref()),
+            Self::Listener(_) | Self::Tick(_) => None,
+        }
     }
+}
+
+/// Sent from the mixer when a track changes state without being told to
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackEvent {
+    Started(String),
+    Finished(String),
+    Looped(String),
+    /// The track is playing but its samples haven't been loaded
+    Underrun(String),
+    /// The wav has been unloaded and its track removed
+    Released(String),
+    /// The output stream failed, not tied to a track. The stream is rebuilt
+    /// and playback carries on from the same positions.
+    DeviceError(String),
+}
 
-    fn play<T>(&mut self)
-    where
-        T: SizedSample + FromSample<f32>,
-    {
-        let config: StreamConfig = self.config.config();
-        let sample_rate = config.sample_rate.0 as f64;
-        let seconds_per_sample = 1.0 / sample_rate;
-        let channels = config.channels as usize;
-
-        let (sender, receiver) = mpsc::channel::<TrackAction>();
-        let wavs = self.wavs.clone();
-        let mut tracks: HashMap<String, Track> = HashMap::new();
-        let mut next_value =
-            move || Self::get_next_audio_value(&wavs, &receiver, &mut tracks, &seconds_per_sample);
-
-        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
-
-        let stream = self
-            .device
-            .build_output_stream(
-                &config.into(),
-                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    write_data(data, channels, &mut next_value)
-                },
-                err_fn,
-                None,
-            )
-            .unwrap();
-
-        stream.play().unwrap();
-
-        let mut last_message = self.receiver.recv().unwrap();
-        while last_message != TrackAction::ShutdownThread {
-            debug!(
-                last_message = format!("{:?}", last_message),
-                "last audio message"
-            );
-            sender.send(last_message).unwrap();
-            last_message = self.receiver.recv().unwrap();
+/// What the output thread sends the mixer. Tracks and effects are named by
+/// id so nothing in a message is freed by the audio callback.
+enum MixerMessage {
+    Command(Command),
+    /// Apply the command on the first sample at or after the time in the
+    /// track
+    Schedule(TrackId, f64, Command),
+    Wav(TrackId, Arc<Wav>),
+    /// Clear the track and drop the mixer's copy of the samples
+    Release(TrackId),
+    /// An analysed block going back to the mixer's analyser
+    Recycle(AnalysisBlock),
+    /// Render this much more audio, only used by stepped outputs
+    Advance(Duration),
+    Shutdown,
+}
+
+impl MixerMessage {
+    /// Effects are built by the output before the message reaches the mixer
+    /// so their buffers are never allocated in the audio callback. Loop
+    /// regions are checked here too so the mixer can use them as they are.
+    fn prepare(self, sample_rate: f64) -> Self {
+        match self {
+            Self::Command(command) => Self::Command(command.prepare(sample_rate)),
+            Self::Schedule(track, time, command) => {
+                Self::Schedule(track, time, command.prepare(sample_rate))
+            }
+            message => message,
         }
     }
+}
 
-    fn get_next_audio_value(
-        wavs: &Arc<RwLock<HashMap<String, Wav>>>,
-        receiver: &Receiver<TrackAction>,
-        tracks: &mut HashMap<String, Track>,
-        seconds_per_sample: &f64,
-    ) -> f32 {
-        while let Ok(action) = receiver.try_recv() {
-            update_track_state(tracks, action);
+impl Debug for MixerMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Command(command) => write!(f, "Command({:?})", command),
+            Self::Schedule(track, time, command) => {
+                write!(f, "Schedule({:?}, {:?}, {:?})", track, time, command)
+            }
+            Self::Wav(track, _) => write!(f, "Wav({:?})", track),
+            Self::Release(track) => write!(f, "Release({:?})", track),
+            Self::Recycle(_) => write!(f, "Recycle"),
+            Self::Advance(time) => write!(f, "Advance({:?})", time),
+            Self::Shutdown => write!(f, "Shutdown"),
         }
-        let mut result = 0.0;
-        for (track_name, track) in tracks {
-            if track.state == TrackState::Playing || track.state == TrackState::Slow {
-                let w = wavs.read().unwrap();
-                let Some(wav) = w.get(track_name) else {
-                    // error!(track=track_name, "Failed to get track audio");
-                    continue;
-                };
-                let samples = &wav.samples;
-                let track_sample_rate = wav.sample_rate as f64;
-                let raw_index = track.time * track_sample_rate;
-                let floor_index = raw_index.floor();
-                let ceil_index = raw_index.ceil();
-                if ceil_index as usize >= samples.len() {
-                    track.state = TrackState::Stopped;
-                    track.time = 0.0;
-                    continue;
-                }
-                let floor_sample = samples[floor_index as usize];
-                let ceil_sample = samples[ceil_index as usize];
-                let lambda = raw_index - floor_index;
-                let sample = lambda * ceil_sample + ((1.0 - lambda) * floor_sample);
-                result += sample / 32_768.0;
-
-                let sample_step = if track.state == TrackState::Slow {
-                    seconds_per_sample * 0.9
-                } else {
-                    *seconds_per_sample
+    }
+}
+
+/// A `TrackAction` with its names swapped for ids
+#[derive(Debug)]
+enum Command {
+    Play(TrackId),
+    Stop(TrackId),
+    Reset(TrackId),
+    Seek(TrackId, f64),
+    Rate(TrackId, PlaybackRate),
+    Route(TrackId, Bus),
+    Loop(TrackId, bool),
+    LoopRegion(TrackId, Option<LoopRegion>),
+    /// Built by `prepare` for each channel of its target, buses have two
+    AddEffect(Target, EffectId, EffectKind, [Option<BuiltEffect>; 2]),
+    RemoveEffect(Target, EffectId),
+    AutomateEffect(Target, EffectId, Automation),
+    ClickTrack(Option<Clicker>),
+    Position(TrackId, Option<Vector3<f32>>),
+    Listener(Listener),
+    Stem(TrackId, TrackId),
+    Mute(TrackId, bool),
+    Tick(Vector3<f32>),
+}
+
+impl Command {
+    fn prepare(self, sample_rate: f64) -> Self {
+        match self {
+            Self::AddEffect(target, effect, kind, _) => {
+                let channels = match target {
+                    Target::Track(_) => 1,
+                    Target::Bus(_) => 2,
                 };
-                track.time += sample_step;
+                let effects =
+                    [0, 1].map(|c| (c < channels).then(|| BuiltEffect::new(kind, sample_rate)));
+                Self::AddEffect(target, effect, kind, effects)
+            }
+            Self::LoopRegion(track, region) => {
+                Self::LoopRegion(track, region.and_then(LoopRegion::checked))
             }
+            command => command,
         }
-
-        // music_wav_second as f32 caused varying rate so use f64 and
-        // convert to f32 at the end. If f64 precision still leads to noticeable
-        // drift on longer tracks this will need refactoring to not use
-        // floats for time calculations
-        result as f32
     }
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
-where
-    T: Sample + FromSample<f32>,
-{
-    for frame in output.chunks_mut(channels) {
-        let value: T = T::from_sample(next_sample());
-        for sample in frame.iter_mut() {
-            *sample = value;
+/// An `EffectTarget` with the track swapped for its id
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Track(TrackId),
+    Bus(Bus),
+}
+
+/// A `TrackEvent` as the mixer sends it, named by id so sending it doesn't
+/// allocate
+#[derive(Debug, Clone, PartialEq)]
+enum MixerEvent {
+    Started(TrackId),
+    Finished(TrackId),
+    Looped(TrackId),
+    Underrun(TrackId),
+    Released(TrackId),
+    /// Only sent from outside the audio callback
+    DeviceError(String),
+}
+
+/// Whatever the mixer lets go of, sent back to the output thread to be freed
+enum Garbage {
+    Wav(Arc<Wav>),
+    Effect(BuiltEffect),
+    Command(Command),
+}
+
+impl Garbage {
+    fn free(self) {
+        match self {
+            Self::Wav(wav) => drop(wav),
+            Self::Effect(effect) => drop(effect),
+            Self::Command(command) => drop(command),
         }
     }
 }
 
-#[derive(Debug)]
-pub enum AudioMessage {
-    Load(String),
-    TrackAction(TrackAction),
+const EVENT_QUEUE_LENGTH: usize = 256;
+const ANALYSIS_QUEUE_LENGTH: usize = 4;
+
+/// Tracks are summed into their bus before the bus effects are applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bus {
+    Music,
+    Sfx,
+    Click,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum TrackAction {
-    Play(String),
-    Stop(String),
-    Reset(String),
-    Slow(String),
-    //Cleanup(String),
-    ShutdownThread,
+impl Bus {
+    const COUNT: usize = 3;
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
 }
 
-struct Track {
-    state: TrackState,
-    time: f64,
+#[derive(Debug, Clone, PartialEq)]
+pub enum EffectTarget {
+    Track(String),
+    Bus(Bus),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum TrackState {
-    Playing,
-    Stopped,
-    Slow,
+pub struct PlaybackRate {
+    pub rate: f64,
+    /// Seconds taken to move from the current rate to `rate`, 0 is instant
+    pub ramp: f64,
+    /// Time-stretch instead of resampling so slowing down keeps the pitch
+    pub preserve_pitch: bool,
 }
 
-fn update_track_state(tracks: &mut HashMap<String, Track>, action: TrackAction) {
-    match action {
-        TrackAction::Reset(track) => {
-            tracks.insert(
-                track,
-                Track {
-                    state: TrackState::Stopped,
-                    time: 0.0,
-                },
-            );
+/// Times in seconds of the track. The last `crossfade` seconds before `end`
+/// are blended with the audio before `start`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopRegion {
+    pub start: f64,
+    pub end: f64,
+    pub crossfade: f64,
+}
+
+impl LoopRegion {
+    /// None for regions that are empty or backwards, which turn looping off.
+    /// The crossfade is cut down to the length of the region.
+    fn checked(self) -> Option<Self> {
+        if self.end <= self.start {
+            warn!(region = format!("{:?}", self), "Ignoring empty loop region");
+            return None;
+        }
+        Some(LoopRegion {
+            crossfade: self.crossfade.clamp(0.0, self.end - self.start),
+            ..self
+        })
+    }
+}
+
+/// A point in a track measured in seconds of its audio, so it follows seeks,
+/// loops and rate changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongTime {
+    pub track: String,
+    pub time: f64,
+}
+
+/// Clicks on every beat row of a map while `track` is playing, with an
+/// accent on each whole beat. Without a track it counts from when it is set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClickTrack {
+    pub track: Option<String>,
+    pub bpm: f64,
+    pub subdivisions: f64,
+    pub start_offset: f64,
+}
+
+/// Where positional sounds are heard from, in world space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Listener {
+    pub position: Vector3<f32>,
+    /// Unit vector pointing to the listener's right
+    pub right: Vector3<f32>,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: Vector3::zeros(),
+            right: Vector3::x(),
         }
-        TrackAction::Play(track) => match tracks.get_mut(track.as_str()) {
-            Some(t) => t.state = TrackState::Playing,
-            None => {
-                tracks.insert(
-                    track,
-                    Track {
-                        time: 0.0,
-                        state: TrackState::Playing,
-                    },
-                );
-            }
-        },
-        TrackAction::Slow(track) => match tracks.get_mut(track.as_str()) {
-            Some(t) => t.state = TrackState::Slow,
-            None => {
-                tracks.insert(
-                    track,
-                    Track {
-                        time: 0.0,
-                        state: TrackState::Slow,
-                    },
-                );
-            }
-        },
-        TrackAction::Stop(track) => match tracks.get_mut(track.as_str()) {
-            Some(t) => t.state = TrackState::Stopped,
-            None => {
-                tracks.insert(
-                    track,
-                    Track {
-                        time: 0.0,
-                        state: TrackState::Stopped,
-                    },
-                );
-            }
-        },
-        // TrackAction::Cleanup(track) => { tracks.remove(&track); },
-        TrackAction::ShutdownThread => (),
     }
 }
@@ -47,6 +47,12 @@ impl Camera {
         orientation * translation
     }
 
+    /// World space direction of the right edge of the screen
+    pub fn right(&self) -> Vector3<GLfloat> {
+        let view = self.transform();
+        vector![view[(0, 0)], view[(0, 1)], view[(0, 2)]].normalize()
+    }
+
     pub fn position(&self) -> (GLfloat, GLfloat, GLfloat) {
         let y_axis = Vector3::y_axis();
         let x_axis = Vector3::x_axis();
@@ -11,6 +11,11 @@ pub const UPBEAT_MAP: &'static str = "upbeat.txt";
 pub const CUBE_MODEL: &'static str = "cube/cube.obj";
 pub const PLANE_MODEL: &'static str = "plane/plane.obj";
 pub const BACKPACK_MODEL: &'static str = "backpack/backpack.obj";
+pub const LEVEL_MODELS: [&'static str; 3] = [CUBE_MODEL, PLANE_MODEL, BACKPACK_MODEL];
+
+// Resources
+pub const HOT_RELOAD_ENV: &'static str = "HOT_RELOAD";
+pub const ASSET_PATH_ENV: &'static str = "ASSET_PATH";
 
 // Movement
 pub const MOVE_SPEED: f32 = 15.0;
@@ -24,6 +29,35 @@ pub const MIN_ZOOM: f32 = -5.0;
 pub const BEAT_SIZE: f32 = 5.0;
 pub const COLUMN_WIDTH: f32 = 2.2;
 
+// Audio
+pub const AUDIO_BACKEND: &'static str = "cpal";
+pub const AUDIO_BACKEND_ENV: &'static str = "AUDIO_BACKEND";
+pub const AUDIO_CAPTURE_FILE: &'static str = "audio_capture.wav";
+pub const SIMULATED_SAMPLE_RATE: u32 = 44_100;
+pub const SIMULATED_BLOCK_FRAMES: usize = 512;
+pub const TAPE_STOP_SECONDS: f64 = 1.5;
+pub const PRACTICE_RATE: f32 = 0.5;
+pub const PAUSE_MUFFLE_EFFECT: &'static str = "pause_muffle";
+pub const PAUSE_MUFFLE_CUTOFF: f64 = 400.0;
+pub const OPEN_CUTOFF: f64 = 20_000.0;
+pub const PAUSE_MUFFLE_RAMP: f64 = 0.3;
+pub const CALIBRATION_BPM: f64 = 100.0;
+pub const CALIBRATION_WARMUP_BEATS: f64 = 2.0;
+pub const CALIBRATION_TAPS: usize = 8;
+pub const DROPPED_STEM: &'static str = "melody";
+pub const STEM_RETURN_STREAK: u32 = 16;
+pub const OBSTACLE_TICK_DISTANCE: f32 = 30.0;
+// Seconds ahead that obstacle ticks are scheduled
+pub const OBSTACLE_TICK_LOOKAHEAD: f32 = 0.25;
+pub const LIGHT_STRENGTH: f32 = 5.0;
+pub const LIGHT_PULSE_STRENGTH: f32 = 10.0;
+pub const LIGHT_PULSE_CUTOFF: f32 = 150.0;
+
+// Settings
+pub const SETTINGS_FILE: &'static str = "settings.txt";
+// Under the platform's user data directory
+pub const USER_DATA_DIR: &str = "opengl_experiment";
+
 // PLANE
 pub const PLANE_WIDTH: f32 = 4.3;
 pub const PLANE_LENGTH: f32 = 201.05;
@@ -13,6 +13,8 @@ pub struct Controller {
     mouse_y: f32,
     zoom: f32,
     buttons_down: Vec<Button>,
+    // Event times of taps this frame, on the same clock as `time`
+    taps: Vec<f64>,
     mouse_click: bool,
     glfw: Glfw,
     events: Receiver<(f64, WindowEvent)>,
@@ -23,6 +25,8 @@ pub enum Button {
     Restart,
     Quit,
     Pause,
+    Practice,
+    Metronome,
     Level(usize),
 }
 
@@ -36,6 +40,7 @@ impl Controller {
             mouse_y: 0.0,
             zoom: 0.0,
             buttons_down: Vec::new(),
+            taps: Vec::new(),
             mouse_click: false,
             glfw,
             events,
@@ -47,8 +52,12 @@ impl Controller {
         self.glfw.poll_events();
         let mut buttons = Vec::with_capacity(16);
         let mut x_set = false;
-        for (_, event) in glfw::flush_messages(&self.events) {
+        self.taps.clear();
+        for (time, event) in glfw::flush_messages(&self.events) {
             match event {
+                WindowEvent::Key(Key::Space, _, Action::Press, _) => {
+                    self.taps.push(time);
+                }
                 WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     buttons.push(Button::Quit);
                 }
@@ -64,6 +73,12 @@ impl Controller {
                 WindowEvent::Key(Key::P, _, Action::Press, _) => {
                     buttons.push(Button::Pause);
                 }
+                WindowEvent::Key(Key::H, _, Action::Press, _) => {
+                    buttons.push(Button::Practice);
+                }
+                WindowEvent::Key(Key::M, _, Action::Press, _) => {
+                    buttons.push(Button::Metronome);
+                }
                 WindowEvent::Key(Key::Right, _, Action::Press, _) => {
                     self.direction_x = 1.0;
                     x_set = true;
@@ -123,6 +138,15 @@ impl Controller {
         &self.buttons_down
     }
 
+    pub fn taps(&self) -> &[f64] {
+        &self.taps
+    }
+
+    /// Seconds since glfw was initialised
+    pub fn time(&self) -> f64 {
+        self.glfw.get_time()
+    }
+
     pub fn direction(&self) -> f32 {
         self.direction_x
     }
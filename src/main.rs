@@ -15,12 +15,18 @@ extern crate nalgebra as na;
 extern crate tracing;
 extern crate tracing_subscriber;
 
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::Instant;
 
+use anyhow::{anyhow, Result};
+use audio::mixdown::{self, MixdownScript};
+use resource::{manager::asset_vfs, pack, vfs};
 use state::game::Game;
-use tracing::{debug, Level};
+use tracing::{debug, error, Level};
 
-fn main() {
+fn main() -> ExitCode {
     // Log setup
     if cfg!(debug_assertions) {
         tracing_subscriber::fmt()
@@ -30,8 +36,49 @@ fn main() {
         tracing_subscriber::fmt().init();
     }
 
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("--mixdown") {
+        return match run_mixdown(&args[2..]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!(err = format!("{:#}", e), "Mixdown failed");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("--pack") {
+        return match run_pack(&args[2..]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!(err = format!("{:#}", e), "Packing failed");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("--audio-devices") {
+        return match audio::output_devices() {
+            Ok(devices) => {
+                for device in devices {
+                    println!("{}", device);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!(err = e.to_string(), "Failed to list audio devices");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     // Program Setup
-    let mut game = Game::new();
+    let asset_paths = args
+        .windows(2)
+        .filter(|flag| flag[0] == "--assets")
+        .map(|flag| PathBuf::from(&flag[1]))
+        .collect();
+    let mut game = Game::new(vfs::search_paths(asset_paths));
     debug!("Game Initialized");
 
     let mut last_time = Instant::now();
@@ -44,5 +91,39 @@ fn main() {
             break;
         }
     }
-    debug!("Game Closed")
+    debug!("Game Closed");
+    ExitCode::SUCCESS
+}
+
+/// `--mixdown <script> <output.wav> [golden.wav]` or `--mixdown --check`.
+/// Wavs are found through the same search paths as the game, without any
+/// `--assets` flags.
+fn run_mixdown(args: &[String]) -> Result<()> {
+    let vfs = asset_vfs(vfs::search_paths(Vec::new()));
+    if let [flag] = args {
+        if flag == "--check" {
+            return mixdown::check(&vfs);
+        }
+    }
+    let [script, output, golden @ ..] = args else {
+        return Err(anyhow!(
+            "Usage: --mixdown <script> <output.wav> [golden.wav] or --mixdown --check"
+        ));
+    };
+    let script = mixdown::load::<MixdownScript>(script, &vfs)?;
+    mixdown::mixdown(script, output, &vfs)?;
+    if let Some(golden) = golden.first() {
+        mixdown::compare(output, golden, &vfs)?;
+    }
+    Ok(())
+}
+
+/// `--pack <dir> <output.pak> [--compress]`
+fn run_pack(args: &[String]) -> Result<()> {
+    let (dir, output, compress) = match args {
+        [dir, output] => (dir, output, false),
+        [dir, output, flag] if flag == "--compress" => (dir, output, true),
+        _ => return Err(anyhow!("Usage: --pack <dir> <output.pak> [--compress]")),
+    };
+    pack::write_pack(dir, output, compress)
 }
@@ -15,11 +15,14 @@ use model_renderer::ModelRenderer;
 use na::Perspective3;
 use point_light_renderer::PointLightRenderer;
 use progress_renderer::ProgressRenderer;
-use tracing::error;
+use tracing::{debug, error};
 
 use crate::config::{PROGRESS_FRAG_SHADER, PROGRESS_VERT_SHADER};
+use crate::resource::cache::{Handle, LoadState};
 use crate::resource::manager::{DataResRec, DataResSender, ResourceManager};
-use crate::resource::model::{Material, Model, Texture};
+use crate::resource::model::{Material, Model, ModelBundle, Texture};
+use crate::resource::progress::{LoadRequest, LoadStage};
+use crate::resource::scope::ScopeStack;
 use crate::shader::PointLight;
 use crate::shape::{QUAD_INDICES, QUAD_VERTICES};
 use crate::state::scenes::SceneManager;
@@ -33,19 +36,29 @@ pub struct Renderer {
     progress: ProgressRenderer,
     resource_manager: Arc<ResourceManager>,
     message_rec: Receiver<RenderMessage>,
-    loading_models: HashSet<String>,
+    scopes: ScopeStack,
+    // Dropped once uploaded, the renderer only keeps what is on the GPU
+    loading: HashMap<String, Handle<ModelBundle>>,
+    // Mesh names and material files of each model
     models: HashMap<String, Vec<String>>,
-    model_sender: DataResSender<Model>,
-    model_rec: DataResRec<Model>,
-    loading_material_files: HashSet<String>,
-    material_files: HashSet<String>,
+    model_materials: HashMap<String, Vec<String>>,
+    // Models edited while running
+    model_sender: DataResSender<ModelBundle>,
+    model_rec: DataResRec<ModelBundle>,
+    // Material names in each file
+    material_files: HashMap<String, Vec<String>>,
     materials: HashMap<String, Material>,
+    // Material files and textures are only sent on their own when they are
+    // edited while running
     material_sender: DataResSender<Vec<Material>>,
     material_rec: DataResRec<Vec<Material>>,
-    loading_textures: HashSet<String>,
     textures: HashSet<String>,
     texture_sender: DataResSender<Texture>,
     texture_rec: DataResRec<Texture>,
+    // The request each loading model is reported to
+    requests: HashMap<String, LoadRequest>,
+    // Paths of shaders that have changed on disk
+    shader_rec: Receiver<String>,
 }
 
 impl Renderer {
@@ -56,6 +69,7 @@ impl Renderer {
         let light_vert_shader = PathBuf::from(LIGHT_VERT_SHADER);
         let light_frag_shader = PathBuf::from(LIGHT_FRAG_SHADER);
         let light = PointLightRenderer::new(
+            &resource_manager,
             &light_vert_shader,
             &light_frag_shader,
             &CUBE_VERTICES,
@@ -66,6 +80,7 @@ impl Renderer {
         let progress_vert_shader = PathBuf::from(PROGRESS_VERT_SHADER);
         let progress_frag_shader = PathBuf::from(PROGRESS_FRAG_SHADER);
         let progress = ProgressRenderer::new(
+            &resource_manager,
             &progress_vert_shader,
             &progress_frag_shader,
             &QUAD_VERTICES,
@@ -76,11 +91,23 @@ impl Renderer {
         let model_vert_shader = PathBuf::from(MODEL_VERT_SHADER);
         let texture_frag_shader = PathBuf::from(TEXTURE_FRAG_SHADER);
 
-        let model = ModelRenderer::new(&model_vert_shader, &texture_frag_shader).unwrap();
+        let model = ModelRenderer::new(&resource_manager, &model_vert_shader, &texture_frag_shader)
+            .unwrap();
 
         let (model_sender, model_rec) = mpsc::channel();
         let (material_sender, material_rec) = mpsc::channel();
         let (texture_sender, texture_rec) = mpsc::channel();
+        let (shader_sender, shader_rec) = mpsc::channel();
+        for shader in [
+            MODEL_VERT_SHADER,
+            TEXTURE_FRAG_SHADER,
+            LIGHT_VERT_SHADER,
+            LIGHT_FRAG_SHADER,
+            PROGRESS_VERT_SHADER,
+            PROGRESS_FRAG_SHADER,
+        ] {
+            resource_manager.watch_file(shader, shader_sender.clone());
+        }
 
         Self {
             light,
@@ -88,12 +115,12 @@ impl Renderer {
             progress,
             resource_manager,
             message_rec,
-            loading_models: HashSet::new(),
+            scopes: ScopeStack::new(),
+            loading: HashMap::new(),
             models: HashMap::new(),
-            loading_material_files: HashSet::new(),
-            material_files: HashSet::new(),
+            model_materials: HashMap::new(),
+            material_files: HashMap::new(),
             materials: HashMap::new(),
-            loading_textures: HashSet::new(),
             textures: HashSet::new(),
             model_sender,
             model_rec,
@@ -101,55 +128,72 @@ impl Renderer {
             material_rec,
             texture_sender,
             texture_rec,
+            requests: HashMap::new(),
+            shader_rec,
         }
     }
 
-    pub fn load_model(&mut self, model: String) {
-        if self.loading_models.contains(&model) || self.models.contains_key(&model) {
-            return;
-        }
-        self.resource_manager
-            .load_model(model.clone(), self.model_sender.clone());
-        self.loading_models.insert(model);
-    }
-
-    pub fn load_materials(&mut self, materials: Vec<String>) {
-        for mat in materials {
-            if self.loading_material_files.contains(&mat) || self.materials.contains_key(&mat) {
-                continue;
+    /// The model is loaded with its materials and textures as one bundle
+    /// through a shared handle, it is reported to the request if given
+    pub fn load_model(&mut self, model: String, request: Option<&LoadRequest>) {
+        let loaded = self.models.contains_key(&model);
+        let loading = self.loading.contains_key(&model);
+        if let Some(request) = request {
+            if loaded {
+                request.set_stage(&model, LoadStage::Done);
+            } else {
+                if loading && request.asset(&model).is_none() {
+                    request.set_stage(&model, LoadStage::Queued);
+                }
+                self.requests.insert(model.clone(), request.clone());
             }
-            self.resource_manager
-                .load_material(mat.clone(), self.material_sender.clone());
-            self.loading_material_files.insert(mat);
         }
-    }
-
-    pub fn load_textures(&mut self, textures: Vec<String>) {
-        for texture in textures {
-            if self.loading_textures.contains(&texture) || self.textures.contains(&texture) {
-                continue;
-            }
-            self.resource_manager
-                .load_texture(texture.clone(), self.texture_sender.clone());
-            self.loading_textures.insert(texture);
+        if loaded || loading {
+            return;
         }
+        let handle = match request {
+            Some(request) => self
+                .resource_manager
+                .handle_for::<ModelBundle>(request, &model),
+            None => self.resource_manager.handle::<ModelBundle>(&model),
+        };
+        self.resource_manager
+            .watch::<ModelBundle>(&model, self.model_sender.clone());
+        self.loading.insert(model, handle);
     }
 
-    pub fn loaded_check(&self) -> (usize, usize) {
-        let loaded_assets = self.models.len() + self.materials.len() + self.textures.len();
-        let loading_assets = self.loading_models.len()
-            + self.loading_material_files.len()
-            + self.loading_textures.len();
-        (loading_assets, loaded_assets)
+    /// Report a finished load to the request it was made for
+    fn finish_request(&mut self, name: &str, loaded: bool) {
+        let Some(request) = self.requests.remove(name) else {
+            return;
+        };
+        let stage = match loaded {
+            true => LoadStage::Done,
+            false => LoadStage::Failed,
+        };
+        request.set_stage(name, stage);
     }
 
     pub fn update(&mut self, window: &Window, scene_manager: &SceneManager) {
         // Check Messages
         while let Ok(message) = self.message_rec.try_recv() {
             match message {
-                RenderMessage::Load(s) => self.load_model(s),
+                RenderMessage::Load(s, request) => {
+                    self.scopes.add(&s);
+                    self.load_model(s, request.as_ref());
+                }
+                RenderMessage::OpenScope => self.scopes.open(),
+                RenderMessage::CloseScope => {
+                    if self.scopes.close().is_none() {
+                        error!("Closing the global render scope");
+                    }
+                    self.release_unscoped();
+                }
             }
         }
+        while let Ok(shader) = self.shader_rec.try_recv() {
+            self.reload_shader(&shader);
+        }
         // Check loading
         self.loading_update();
 
@@ -198,81 +242,258 @@ impl Renderer {
     }
 
     fn loading_update(&mut self) {
-        // Models
-        if !self.loading_models.is_empty() {
-            while let Ok((model_name, res)) = self.model_rec.try_recv() {
-                self.loading_models.remove(&model_name);
-                let materials = match res {
-                    Ok(Model { meshes, materials }) => {
-                        let ms: Vec<String> = meshes.iter().map(|m| m.name.to_string()).collect();
-                        self.model.load_meshes(meshes);
-                        self.models.insert(model_name, ms);
-                        materials
-                    }
-                    Err(e) => {
-                        error!(
-                            error = e.backtrace().to_string(),
-                            model = &model_name,
-                            "Failed to load model"
-                        );
-                        continue;
-                    }
-                };
+        // Models, taken so each finished one can be uploaded
+        let mut loading = std::mem::take(&mut self.loading);
+        loading.retain(|model_name, handle| {
+            match handle.state() {
+                LoadState::Pending => return true,
+                LoadState::Ready(bundle) => {
+                    self.upload_bundle(model_name, &bundle);
+                    self.finish_request(model_name, true);
+                }
+                LoadState::Failed(e) => {
+                    self.finish_request(model_name, false);
+                    error!(
+                        error = format!("{:#}", e),
+                        model = model_name,
+                        "Failed to load model"
+                    );
+                }
+            }
+            false
+        });
+        self.loading = loading;
 
-                self.load_materials(materials);
+        // Models edited while running, reloads of models that have since
+        // been released are ignored
+        while let Ok((model_name, res)) = self.model_rec.try_recv() {
+            if !self.models.contains_key(&model_name) {
+                continue;
+            }
+            match res {
+                Ok(bundle) => self.upload_bundle(&model_name, &bundle),
+                Err(e) => {
+                    error!(
+                        error = format!("{:#}", e),
+                        model = &model_name,
+                        "Failed to reload model"
+                    );
+                }
             }
         }
 
-        // Materials
-        if !self.loading_material_files.is_empty() {
-            while let Ok((material_name, res)) = self.material_rec.try_recv() {
-                self.loading_material_files.remove(&material_name);
-                let textures = match res {
-                    Ok(mats) => {
-                        let mut textures = Vec::new();
-                        for mat in mats {
-                            textures.push(mat.diffuse_map.clone());
-                            textures.push(mat.specular_map.clone());
-                            self.materials.insert(mat.name.clone(), mat);
-                        }
-                        self.material_files.insert(material_name);
-                        textures
-                    }
-                    Err(e) => {
-                        let error: String = e.chain().map(|s| s.to_string()).collect();
-                        error!(
-                            error = error,
-                            material = &material_name,
-                            "Failed to load material"
-                        );
-                        continue;
+        // Material files edited while running, the reloads of files that
+        // have since been released are ignored
+        while let Ok((material_file, res)) = self.material_rec.try_recv() {
+            if !self.material_files.contains_key(&material_file) {
+                continue;
+            }
+            match res {
+                Ok(materials) => {
+                    // Textures the edit started using are loaded like reloads
+                    let new_textures: HashSet<String> = materials
+                        .iter()
+                        .flat_map(|m| [m.diffuse_map.clone(), m.specular_map.clone()])
+                        .filter(|texture| !self.textures.contains(texture))
+                        .collect();
+                    self.upload_materials(material_file, materials);
+                    for texture in new_textures {
+                        self.resource_manager
+                            .load::<Texture>(texture, self.texture_sender.clone());
                     }
-                };
+                }
+                Err(e) => {
+                    error!(
+                        error = format!("{:#}", e),
+                        material = &material_file,
+                        "Failed to reload material"
+                    );
+                }
+            };
+        }
 
-                self.load_textures(textures);
+        // Textures edited while running or used by an edited material
+        while let Ok((texture_name, res)) = self.texture_rec.try_recv() {
+            let used = self
+                .materials
+                .values()
+                .any(|m| m.diffuse_map == texture_name || m.specular_map == texture_name);
+            if !used {
+                continue;
             }
+            match res {
+                Ok(texture) => self.upload_texture(&texture),
+                Err(e) => {
+                    error!(
+                        error = format!("{:#}", e),
+                        texture = &texture_name,
+                        "Failed to reload Texture"
+                    );
+                }
+            };
         }
+    }
 
-        // Textures
-        if !self.loading_textures.is_empty() {
-            while let Ok((texture_name, res)) = self.texture_rec.try_recv() {
-                self.loading_textures.remove(&texture_name);
-                match res {
-                    Ok(texture) => {
-                        self.model.load_texture(texture);
-                        self.textures.insert(texture_name);
-                    }
-                    Err(e) => {
-                        error!(
-                            error = e.to_string(),
-                            texture = &texture_name,
-                            "Failed to load Texture"
-                        );
-                        continue;
-                    }
-                };
+    /// Textures other models already uploaded are kept, a reloaded bundle
+    /// replaces the meshes
+    fn upload_bundle(&mut self, model_name: &str, bundle: &ModelBundle) {
+        let ModelBundle {
+            model: Model { meshes, materials },
+            material_files,
+            textures,
+        } = bundle;
+        let ms: Vec<String> = meshes.iter().map(|m| m.name.to_string()).collect();
+        self.model.load_meshes(meshes);
+        self.models.insert(model_name.to_string(), ms);
+        self.model_materials
+            .insert(model_name.to_string(), materials.clone());
+        let mut used_textures = HashSet::new();
+        for (file, materials) in material_files {
+            used_textures.extend(
+                materials
+                    .iter()
+                    .flat_map(|m| [m.diffuse_map.clone(), m.specular_map.clone()]),
+            );
+            self.upload_materials(file.clone(), materials.clone());
+        }
+        for texture in textures {
+            if !self.textures.contains(&texture.name) {
+                self.upload_texture(texture);
             }
         }
+        // Left out of the bundle as resident but released while it loaded,
+        // loaded like reloads
+        for texture in used_textures {
+            if !self.textures.contains(&texture) {
+                self.resource_manager
+                    .load::<Texture>(texture, self.texture_sender.clone());
+            }
+        }
+    }
+
+    fn upload_materials(&mut self, file: String, materials: Vec<Material>) {
+        self.resource_manager
+            .watch::<Material>(&file, self.material_sender.clone());
+        let mut names = Vec::new();
+        for material in materials {
+            names.push(material.name.clone());
+            self.materials.insert(material.name.clone(), material);
+        }
+        self.material_files.insert(file, names);
+    }
+
+    /// Replaces the texture if it's already uploaded
+    fn upload_texture(&mut self, texture: &Texture) {
+        self.resource_manager
+            .watch::<Texture>(&texture.name, self.texture_sender.clone());
+        let name = texture.name.clone();
+        self.model.load_texture(texture);
+        self.resource_manager.set_resident::<Texture>(&name, true);
+        self.textures.insert(name);
+    }
+}
+
+impl Renderer {
+    /// Rebuild whichever renderer uses the shader, on failure the old
+    /// shaders are kept
+    fn reload_shader(&mut self, shader: &str) {
+        let result = if [MODEL_VERT_SHADER, TEXTURE_FRAG_SHADER].contains(&shader) {
+            self.model.reload_shaders(
+                &self.resource_manager,
+                &PathBuf::from(MODEL_VERT_SHADER),
+                &PathBuf::from(TEXTURE_FRAG_SHADER),
+            )
+        } else if [LIGHT_VERT_SHADER, LIGHT_FRAG_SHADER].contains(&shader) {
+            PointLightRenderer::new(
+                &self.resource_manager,
+                &PathBuf::from(LIGHT_VERT_SHADER),
+                &PathBuf::from(LIGHT_FRAG_SHADER),
+                &CUBE_VERTICES,
+                &CUBE_INDICES,
+            )
+            .map(|light| {
+                self.light.delete();
+                self.light = light;
+            })
+        } else if [PROGRESS_VERT_SHADER, PROGRESS_FRAG_SHADER].contains(&shader) {
+            ProgressRenderer::new(
+                &self.resource_manager,
+                &PathBuf::from(PROGRESS_VERT_SHADER),
+                &PathBuf::from(PROGRESS_FRAG_SHADER),
+                &QUAD_VERTICES,
+                &QUAD_INDICES,
+            )
+            .map(|progress| {
+                self.progress.delete();
+                self.progress = progress;
+            })
+        } else {
+            return;
+        };
+        if let Err(e) = result {
+            error!(error = format!("{:?}", e), shader = shader, "Failed to reload shader");
+        }
+    }
+
+    /// Free the GPU objects and materials of every model no open scope
+    /// holds. Materials and textures shared with a model that is still
+    /// held are kept.
+    fn release_unscoped(&mut self) {
+        // Dropping the handle lets the load be freed once no one else holds it
+        let scopes = &self.scopes;
+        let requests = &mut self.requests;
+        self.loading.retain(|model, _| {
+            let held = scopes.contains(model);
+            if !held {
+                requests.remove(model);
+            }
+            held
+        });
+
+        let released: Vec<String> = self
+            .models
+            .keys()
+            .filter(|model| !self.scopes.contains(model))
+            .cloned()
+            .collect();
+        for model in &released {
+            debug!(model = model, "Releasing model");
+            if let Some(meshes) = self.models.remove(model) {
+                self.model.unload_meshes(&meshes);
+            }
+            self.model_materials.remove(model);
+        }
+
+        let held_files: HashSet<&String> = self.model_materials.values().flatten().collect();
+        let released_files: Vec<String> = self
+            .material_files
+            .keys()
+            .filter(|file| !held_files.contains(file))
+            .cloned()
+            .collect();
+        for file in released_files {
+            for material in self.material_files.remove(&file).unwrap_or_default() {
+                self.materials.remove(&material);
+            }
+        }
+
+        let held_textures: HashSet<&String> = self
+            .materials
+            .values()
+            .flat_map(|m| [&m.diffuse_map, &m.specular_map])
+            .collect();
+        let released_textures: Vec<String> = self
+            .textures
+            .iter()
+            .filter(|texture| !held_textures.contains(texture))
+            .cloned()
+            .collect();
+        for texture in released_textures {
+            self.model.unload_texture(&texture);
+            self.resource_manager
+                .set_resident::<Texture>(&texture, false);
+            self.textures.remove(&texture);
+        }
     }
 }
 
@@ -284,5 +505,10 @@ fn clear() {
 }
 
 pub enum RenderMessage {
-    Load(String),
+    /// Its progress, and that of its materials and textures, is reported to
+    /// the request if given
+    Load(String, Option<LoadRequest>),
+    /// Models loaded from now on are released when the scope is closed
+    OpenScope,
+    CloseScope,
 }
@@ -12,7 +12,7 @@ use na::Matrix4;
 use tracing::error;
 
 use crate::{
-    resource::model::Texture,
+    resource::{manager::ResourceManager, model::Texture},
     shader::{
         create_shader, template_dir_light, template_point_light, DirLight, DirLightProp,
         OpenGLError, PointLight, PointLightProp,
@@ -36,11 +36,15 @@ pub struct ModelRenderer {
 }
 
 impl ModelRenderer {
-    pub fn new(vert_shader: &PathBuf, frag_shader: &PathBuf) -> Result<Self, OpenGLError> {
+    pub fn new(
+        resource_manager: &ResourceManager,
+        vert_shader: &PathBuf,
+        frag_shader: &PathBuf,
+    ) -> Result<Self, OpenGLError> {
         unsafe {
             // Create program
-            let vert = create_shader(vert_shader, gl::VERTEX_SHADER)?;
-            let frag = create_shader(frag_shader, gl::FRAGMENT_SHADER)?;
+            let vert = create_shader(resource_manager, vert_shader, gl::VERTEX_SHADER)?;
+            let frag = create_shader(resource_manager, frag_shader, gl::FRAGMENT_SHADER)?;
 
             let program = gl::CreateProgram();
             gl::AttachShader(program, vert);
@@ -132,7 +136,28 @@ impl ModelRenderer {
         }
     }
 
-    pub fn load_meshes(&mut self, meshes: Vec<Mesh>) {
+    /// Build a new program from the shader files, keeping the loaded meshes
+    /// and textures. The old program is kept if the new one fails.
+    pub fn reload_shaders(
+        &mut self,
+        resource_manager: &ResourceManager,
+        vert_shader: &PathBuf,
+        frag_shader: &PathBuf,
+    ) -> Result<(), OpenGLError> {
+        let mut reloaded = Self::new(resource_manager, vert_shader, frag_shader)?;
+        reloaded.parsed_meshes = mem::take(&mut self.parsed_meshes);
+        reloaded.textures = mem::take(&mut self.textures);
+        unsafe {
+            gl::DeleteProgram(self.shader_id);
+        }
+        *self = reloaded;
+        Ok(())
+    }
+
+    /// Meshes that are already loaded are replaced
+    pub fn load_meshes(&mut self, meshes: &[Mesh]) {
+        let names: Vec<String> = meshes.iter().map(|m| m.name.clone()).collect();
+        self.unload_meshes(&names);
         unsafe {
             gl::UseProgram(self.shader_id);
 
@@ -186,18 +211,43 @@ impl ModelRenderer {
                 gl::EnableVertexAttribArray(2);
 
                 self.parsed_meshes.insert(
-                    meshes.name,
+                    meshes.name.clone(),
                     ParsedMesh {
                         vao,
-                        indices: meshes.indices,
-                        material: meshes.material,
+                        vbo,
+                        ebo,
+                        indices: meshes.indices.clone(),
+                        material: meshes.material.clone(),
                     },
                 );
             }
         }
     }
 
-    pub fn load_texture(&mut self, texture: Texture) {
+    pub fn unload_meshes(&mut self, names: &[String]) {
+        for name in names {
+            let Some(mesh) = self.parsed_meshes.remove(name) else {
+                continue;
+            };
+            unsafe {
+                gl::DeleteVertexArrays(1, &mesh.vao);
+                gl::DeleteBuffers(1, &mesh.vbo);
+                gl::DeleteBuffers(1, &mesh.ebo);
+            }
+        }
+    }
+
+    pub fn unload_texture(&mut self, name: &str) {
+        if let Some(texture) = self.textures.remove(name) {
+            unsafe {
+                gl::DeleteTextures(1, &texture);
+            }
+        }
+    }
+
+    /// A texture that is already loaded is replaced
+    pub fn load_texture(&mut self, texture: &Texture) {
+        self.unload_texture(&texture.name);
         unsafe {
             gl::UseProgram(self.shader_id);
 
@@ -224,7 +274,7 @@ impl ModelRenderer {
                 &texture_bytes[0] as *const _ as *const c_void,
             );
             gl::GenerateMipmap(gl::TEXTURE_2D);
-            self.textures.insert(name, texture_location);
+            self.textures.insert(name.clone(), texture_location);
         }
     }
 
@@ -382,6 +432,8 @@ struct MaterialUniform {
 
 struct ParsedMesh {
     vao: u32,
+    vbo: u32,
+    ebo: u32,
     indices: Vec<u32>,
     material: String,
 }
@@ -3,6 +3,7 @@ use std::mem;
 use std::path::PathBuf;
 use std::ptr;
 
+use crate::resource::manager::ResourceManager;
 use crate::shader::{create_shader, OpenGLError};
 use crate::state::scenes::PointLight;
 
@@ -13,6 +14,8 @@ use na::Matrix4;
 pub struct PointLightRenderer {
     shader_id: u32,
     vao: u32,
+    vbo: u32,
+    ebo: u32,
     color_uniform: i32,
     view_uniform: i32,
     projection_uniform: i32,
@@ -22,6 +25,7 @@ pub struct PointLightRenderer {
 
 impl PointLightRenderer {
     pub fn new(
+        resource_manager: &ResourceManager,
         vert_shader: &PathBuf,
         frag_shader: &PathBuf,
         vertices: &[f32],
@@ -29,8 +33,8 @@ impl PointLightRenderer {
     ) -> Result<Self, OpenGLError> {
         unsafe {
             // Create Program
-            let vert = create_shader(vert_shader, gl::VERTEX_SHADER)?;
-            let frag = create_shader(frag_shader, gl::FRAGMENT_SHADER)?;
+            let vert = create_shader(resource_manager, vert_shader, gl::VERTEX_SHADER)?;
+            let frag = create_shader(resource_manager, frag_shader, gl::FRAGMENT_SHADER)?;
 
             let program = gl::CreateProgram();
             gl::AttachShader(program, vert);
@@ -91,6 +95,8 @@ impl PointLightRenderer {
             Ok(Self {
                 shader_id: program,
                 vao,
+                vbo,
+                ebo,
                 color_uniform,
                 view_uniform,
                 projection_uniform,
@@ -100,6 +106,16 @@ impl PointLightRenderer {
         }
     }
 
+    /// Free the program and buffers, used when it is rebuilt with new shaders
+    pub fn delete(&self) {
+        unsafe {
+            gl::DeleteProgram(self.shader_id);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteBuffers(1, &self.ebo);
+        }
+    }
+
     pub fn draw(&self, lights: &[PointLight], view: Matrix4<f32>, projection: Matrix4<f32>) {
         unsafe {
             gl::UseProgram(self.shader_id);
@@ -3,6 +3,7 @@ use std::mem;
 use std::path::PathBuf;
 use std::ptr;
 
+use crate::resource::manager::ResourceManager;
 use crate::shader::{create_shader, OpenGLError};
 use crate::state::scenes::UiElement;
 
@@ -13,6 +14,8 @@ use tracing::debug;
 pub struct ProgressRenderer {
     shader_id: u32,
     vao: u32,
+    vbo: u32,
+    ebo: u32,
     base_color_uniform: i32,
     progress_color_uniform: i32,
     progress_uniform: i32,
@@ -24,6 +27,7 @@ pub struct ProgressRenderer {
 
 impl ProgressRenderer {
     pub fn new(
+        resource_manager: &ResourceManager,
         vert_shader: &PathBuf,
         frag_shader: &PathBuf,
         vertices: &[f32],
@@ -31,8 +35,8 @@ impl ProgressRenderer {
     ) -> Result<Self, OpenGLError> {
         unsafe {
             // Create Program
-            let vert = create_shader(vert_shader, gl::VERTEX_SHADER)?;
-            let frag = create_shader(frag_shader, gl::FRAGMENT_SHADER)?;
+            let vert = create_shader(resource_manager, vert_shader, gl::VERTEX_SHADER)?;
+            let frag = create_shader(resource_manager, frag_shader, gl::FRAGMENT_SHADER)?;
 
             let program = gl::CreateProgram();
             gl::AttachShader(program, vert);
@@ -95,6 +99,8 @@ impl ProgressRenderer {
             Ok(Self {
                 shader_id: program,
                 vao,
+                vbo,
+                ebo,
                 base_color_uniform,
                 progress_color_uniform,
                 progress_uniform,
@@ -106,6 +112,16 @@ impl ProgressRenderer {
         }
     }
 
+    /// Free the program and buffers, used when it is rebuilt with new shaders
+    pub fn delete(&self) {
+        unsafe {
+            gl::DeleteProgram(self.shader_id);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteBuffers(1, &self.ebo);
+        }
+    }
+
     pub fn draw(&self, bars: Vec<UiElement>) {
         unsafe {
             gl::UseProgram(self.shader_id);
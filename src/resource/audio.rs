@@ -1,4 +1,9 @@
-use std::{error::Error, fmt::Display, fs::OpenOptions, io::Read};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
 
 use anyhow::Result;
 use tracing::debug;
@@ -16,8 +21,8 @@ pub struct Wav {
 
 impl Loadable for Wav {
     type Output = Self;
-    fn load(path: &str) -> Result<Self> {
-        let mut file = OpenOptions::new().read(true).open(&path)?;
+    fn parse(path: &str, bytes: Vec<u8>) -> Result<Self> {
+        let mut file = bytes.as_slice();
 
         let mut file_header: [u8; WAV_HEADER_SIZE] = [0; WAV_HEADER_SIZE];
         let bytes_read = file.read(&mut file_header)?;
@@ -42,18 +47,13 @@ impl Loadable for Wav {
         let data_size_bytes: [u8; 4] = read_from_buffer(&file_header[40..44]);
         let data_size = u32::from_le_bytes(data_size_bytes);
 
-        let samples = unsafe {
-            let mut sample_buffer: Vec<u8> = Vec::with_capacity(data_size as usize);
-            let ds = data_size as usize;
-            sample_buffer.set_len(ds);
-            // TODO: Fix interrupt handling. n often less than ds.
-            let _ = file.read(&mut sample_buffer)?;
-            // if n != ds {
-            //     return Err(ParseWavError::DataSizeInconsistent(n, ds).into());
-            // }
-            parse_samples(&sample_buffer)
+        let ds = data_size as usize;
+        let Some(sample_buffer) = file.get(..ds) else {
+            return Err(ParseWavError::DataSizeInconsistent(file.len(), ds).into());
         };
+        let samples = parse_samples(sample_buffer);
         debug!(
+            path = path,
             correct_subtype = correct_subtype,
             correct_filetype = correct_filetype,
             filesize = filesize,
@@ -73,6 +73,76 @@ impl Loadable for Wav {
     }
 }
 
+/// Streams 16 bit samples with any number of channels to a wav file, the
+/// sizes in the header are filled in by `finish`
+pub struct WavWriter {
+    file: File,
+    sample_rate: u32,
+    channels: u16,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32, channels: u16) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = WavWriter {
+            file,
+            sample_rate,
+            channels,
+            data_size: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Samples of each frame are interleaved
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let int = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.extend_from_slice(&int.to_le_bytes());
+        }
+        self.file.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let channels = self.channels;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+        let bytes_per_second = self.sample_rate * block_align as u32;
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(36 + self.data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&bytes_per_second.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits_per_sample.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_size.to_le_bytes());
+        self.file.write_all(&header)?;
+        Ok(())
+    }
+}
+
 fn read_from_buffer<const T: usize>(slice: &[u8]) -> [u8; T] {
     let mut bytes = [0; T];
     bytes.clone_from_slice(slice);
@@ -94,7 +164,7 @@ fn parse_samples(bytes: &[u8]) -> Vec<f64> {
 #[derive(Debug)]
 enum ParseWavError {
     HeaderTooSmall(usize),
-    // DataSizeInconsistent(usize, usize),
+    DataSizeInconsistent(usize, usize),
 }
 
 impl Display for ParseWavError {
@@ -105,7 +175,9 @@ impl Display for ParseWavError {
                 "Expected {} bytes in header but only found {}",
                 WAV_HEADER_SIZE, bytes
             ),
-            // Self::DataSizeInconsistent(bytes, expected) => write!(f, "Expected {} bytes in data but found {}", expected, bytes),
+            Self::DataSizeInconsistent(bytes, expected) => {
+                write!(f, "Expected {} bytes in data but found {}", expected, bytes)
+            }
         }
     }
 }
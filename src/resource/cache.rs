@@ -0,0 +1,190 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// A shared reference to an asset owned by the resource manager. Cloning it
+/// is cheap, the asset is freed once every handle to it has been dropped.
+pub struct Handle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+struct Slot<T> {
+    name: String,
+    state: Mutex<LoadState<T>>,
+}
+
+pub enum LoadState<T> {
+    Pending,
+    Ready(Arc<T>),
+    Failed(Arc<anyhow::Error>),
+}
+
+impl<T> Clone for LoadState<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Pending => Self::Pending,
+            Self::Ready(asset) => Self::Ready(asset.clone()),
+            Self::Failed(e) => Self::Failed(e.clone()),
+        }
+    }
+}
+
+impl<T> Handle<T> {
+    pub fn name(&self) -> &str {
+        &self.slot.name
+    }
+
+    pub fn state(&self) -> LoadState<T> {
+        self.slot.state.lock().unwrap().clone()
+    }
+
+    /// The asset once it has loaded
+    pub fn get(&self) -> Option<Arc<T>> {
+        match &*self.slot.state.lock().unwrap() {
+            LoadState::Ready(asset) => Some(asset.clone()),
+            _ => None,
+        }
+    }
+
+    /// How many handles share the asset, including this one
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.slot)
+    }
+
+    pub fn downgrade(&self) -> WeakHandle<T> {
+        WeakHandle {
+            slot: Arc::downgrade(&self.slot),
+        }
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Handle {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T> Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match *self.slot.state.lock().unwrap() {
+            LoadState::Pending => "Pending",
+            LoadState::Ready(_) => "Ready",
+            LoadState::Failed(_) => "Failed",
+        };
+        write!(f, "Handle({:?}, {})", self.name(), state)
+    }
+}
+
+/// Only holds weak references so it never keeps an asset alive by itself
+#[derive(Default)]
+pub struct AssetCache {
+    slots: Mutex<HashMap<(TypeId, String), Box<dyn Entry>>>,
+}
+
+impl AssetCache {
+    /// The existing handle for the asset, or a new pending one and true if
+    /// it needs loading
+    pub fn get_or_insert<T: Send + Sync + 'static>(&self, name: &str) -> (Handle<T>, bool) {
+        let mut slots = self.slots.lock().unwrap();
+        let key = (TypeId::of::<T>(), name.to_string());
+        let existing = slots
+            .get(&key)
+            .and_then(|entry| entry.as_any().downcast_ref::<Weak<Slot<T>>>())
+            .and_then(Weak::upgrade);
+        if let Some(slot) = existing {
+            return (Handle { slot }, false);
+        }
+
+        slots.retain(|_, entry| entry.alive());
+        let slot = Arc::new(Slot {
+            name: name.to_string(),
+            state: Mutex::new(LoadState::Pending),
+        });
+        slots.insert(key, Box::new(Arc::downgrade(&slot)));
+        (Handle { slot }, true)
+    }
+}
+
+impl Debug for AssetCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "AssetCache({} entries)",
+            self.slots.lock().unwrap().len()
+        )
+    }
+}
+
+/// Fill in a pending handle, dropped if nothing wants the asset any more
+pub fn finish<T>(slot: &WeakHandle<T>, result: anyhow::Result<T>) {
+    let Some(slot) = slot.slot.upgrade() else {
+        return;
+    };
+    *slot.state.lock().unwrap() = match result {
+        Ok(asset) => LoadState::Ready(Arc::new(asset)),
+        Err(e) => LoadState::Failed(Arc::new(e)),
+    };
+}
+
+/// Held by the worker loading the asset so it doesn't count as a reference
+pub struct WeakHandle<T> {
+    slot: Weak<Slot<T>>,
+}
+
+impl<T> WeakHandle<T> {
+    /// How many handles share the asset, 0 once they have all been dropped
+    pub fn ref_count(&self) -> usize {
+        self.slot.strong_count()
+    }
+}
+
+trait Entry: Send {
+    fn alive(&self) -> bool;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Send + Sync + 'static> Entry for Weak<Slot<T>> {
+    fn alive(&self) -> bool {
+        self.strong_count() > 0
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_name_shares_a_handle_until_every_holder_drops_it() {
+        let cache = AssetCache::default();
+        let (first, new) = cache.get_or_insert::<String>("a");
+        assert!(new);
+        let (second, new) = cache.get_or_insert::<String>("a");
+        assert!(!new);
+        assert_eq!(first.ref_count(), 2);
+        // The same name as another type is a different asset
+        let (_, new) = cache.get_or_insert::<u32>("a");
+        assert!(new);
+
+        let slot = first.downgrade();
+        finish(&slot, Ok("loaded".to_string()));
+        assert!(Arc::ptr_eq(&first.get().unwrap(), &second.get().unwrap()));
+
+        drop(first);
+        drop(second);
+        assert_eq!(slot.ref_count(), 0);
+        // Finishing a load nobody wants any more does nothing
+        finish(&slot, Ok("late".to_string()));
+        let (again, new) = cache.get_or_insert::<String>("a");
+        assert!(new);
+        assert!(again.get().is_none());
+    }
+}
@@ -1,111 +1,355 @@
+use std::any::{type_name, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::{self, Sender};
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+
+use crate::config::HOT_RELOAD_ENV;
 
 use super::audio::Wav;
+use super::cache::{self, AssetCache, Handle};
 use super::map::Map;
-use super::model::{Material, Model, Texture};
+use super::model::{Material, ModelBundle, Texture};
+use super::progress::{LoadRequest, LoadStage, RequestId};
+use super::vfs::Vfs;
+use super::watch::Watcher;
 
+/// Loads resources on a pool of worker threads. Requests are taken in order
+/// but independent loads finish in whatever order they complete.
 #[derive(Debug)]
 pub struct ResourceManager {
     req_sender: Sender<DataReq>,
-    shutdown_sender: Sender<()>,
+    // Shared with the workers, packs are only mounted before they start
+    vfs: Arc<Vfs>,
+    // Only looked up when queueing loads so the workers never wait on it
+    registry: Mutex<LoaderRegistry>,
+    // Dependencies already held by the systems that load them
+    resident: Arc<Resident>,
+    next_request: AtomicU64,
+    cache: AssetCache,
+    // Behind a mutex so cleanup can take them to join through the Arc
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    // Only running when hot reloading is turned on
+    watcher: Mutex<Option<Watcher>>,
 }
 
 impl ResourceManager {
-    pub fn new() -> Self {
+    /// Assets are looked for under each search path, see `vfs::search_paths`
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        let vfs = Arc::new(asset_vfs(search_paths));
+
         let (req_sender, req_receiver) = mpsc::channel::<DataReq>();
-        let (shutdown_sender, shutdown_receiver) = mpsc::channel::<()>();
-        let _ = thread::spawn(move || {
-            run_io(req_receiver, shutdown_receiver);
-        });
+        let req_receiver = Arc::new(Mutex::new(req_receiver));
+        let worker_count = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(MAX_WORKERS);
+        debug!(workers = worker_count, "Starting resource workers");
+        let workers = (0..worker_count)
+            .map(|_| {
+                let req_receiver = req_receiver.clone();
+                thread::spawn(move || run_io(req_receiver))
+            })
+            .collect();
 
-        Self {
+        let manager = Self {
             req_sender,
-            shutdown_sender,
+            vfs,
+            registry: Mutex::new(LoaderRegistry::default()),
+            resident: Arc::default(),
+            next_request: AtomicU64::new(0),
+            cache: AssetCache::default(),
+            workers: Mutex::new(workers),
+            watcher: Mutex::new(hot_reload().then(Watcher::new)),
+        };
+        // Materials and textures are named by their path from the model
+        manager.register::<Wav>(AUDIO_LOCATION, &["wav"]);
+        manager.register::<Map>(MAP_LOCATION, &["txt"]);
+        manager.register::<ModelBundle>(MODEL_LOCATION, &["obj"]);
+        manager.register::<Material>("", &["mtl"]);
+        manager.register::<Texture>("", &["png", "jpg", "jpeg"]);
+        manager
+    }
+
+    /// Load files with these extensions as `T` from `location`. Registering
+    /// the same type and extension again moves it to the new location.
+    pub fn register<T: Loadable + 'static>(&self, location: &'static str, extensions: &[&str]) {
+        self.registry
+            .lock()
+            .unwrap()
+            .register::<T>(location, extensions);
+    }
+
+    /// A new group to load assets under with `load_for` and `handle_for`
+    pub fn request(&self) -> LoadRequest {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        LoadRequest::new(RequestId(id))
+    }
+
+    /// Load any registered type on a worker, the result is sent back with
+    /// the name it was requested by
+    pub fn load<T>(&self, name: String, callback_sender: DataResSender<T::Output>)
+    where
+        T: Loadable + 'static,
+        T::Output: Send + 'static,
+    {
+        self.queue_load::<T>(name, callback_sender, None);
+    }
+
+    /// `load` reporting its progress to the request, a cancelled load is
+    /// sent back as an error
+    pub fn load_for<T>(
+        &self,
+        request: &LoadRequest,
+        name: String,
+        callback_sender: DataResSender<T::Output>,
+    ) where
+        T: Loadable + 'static,
+        T::Output: Send + 'static,
+    {
+        request.set_stage(&name, LoadStage::Queued);
+        self.queue_load::<T>(name, callback_sender, Some(request.clone()));
+    }
+
+    fn queue_load<T>(
+        &self,
+        name: String,
+        callback_sender: DataResSender<T::Output>,
+        request: Option<LoadRequest>,
+    ) where
+        T: Loadable + 'static,
+        T::Output: Send + 'static,
+    {
+        let Some(location) = self.registry.lock().unwrap().location::<T>(&name) else {
+            if let Some(request) = request {
+                request.set_stage(&name, LoadStage::Failed);
+            }
+            let error = ResourceError::Unregistered(type_name::<T>(), name.clone());
+            let _ = callback_sender.send((name, Err(error.into())));
+            return;
+        };
+        let (vfs, resident) = (self.vfs.clone(), self.resident.clone());
+        let job = Box::new(move || {
+            let request = request.as_ref();
+            load::<T>(&vfs, &resident, location, name, callback_sender, request)
+        });
+        self.req_sender.send(DataReq::Load(job)).unwrap();
+    }
+
+    /// Load the resource again and send it to `callback_sender` whenever its
+    /// file changes. Does nothing unless hot reloading is turned on.
+    pub fn watch<T>(&self, name: &str, callback_sender: DataResSender<T::Output>)
+    where
+        T: Loadable + 'static,
+        T::Output: Send + 'static,
+    {
+        let watcher = self.watcher.lock().unwrap();
+        let location = self.registry.lock().unwrap().location::<T>(name);
+        let (Some(watcher), Some(location)) = (&*watcher, location) else {
+            return;
+        };
+        let Some(file) = self.watch_target(&(location.to_string() + name)) else {
+            return;
+        };
+        let req_sender = self.req_sender.clone();
+        let (vfs, resident) = (self.vfs.clone(), self.resident.clone());
+        let name = name.to_string();
+        let on_change = move || {
+            let (vfs, resident) = (vfs.clone(), resident.clone());
+            let (name, sender) = (name.clone(), callback_sender.clone());
+            let job = Box::new(move || load::<T>(&vfs, &resident, location, name, sender, None));
+            let _ = req_sender.send(DataReq::Load(job));
+        };
+        watcher.watch(file.to_string_lossy().into_owned(), Arc::new(on_change));
+    }
+
+    /// Sends the path whenever the file changes, for files that aren't read
+    /// through a `Loadable` such as shaders
+    pub fn watch_file(&self, path: &str, sender: Sender<String>) {
+        let watcher = self.watcher.lock().unwrap();
+        let Some(watcher) = &*watcher else {
+            return;
+        };
+        let Some(file) = self.watch_target(path) else {
+            return;
+        };
+        // Sent by the path it was asked for rather than where it was found
+        let changed = path.to_string();
+        let on_change = move || {
+            let _ = sender.send(changed.clone());
+        };
+        watcher.watch(file.to_string_lossy().into_owned(), Arc::new(on_change));
+    }
+
+    /// Only loose files can be edited, and not ones a pack shadows since
+    /// their edits would never be read
+    fn watch_target(&self, path: &str) -> Option<PathBuf> {
+        if self.vfs.packed(path) {
+            warn!(path = path, "Not hot reloading asset read from a pack");
+            return None;
         }
+        self.vfs.resolve(path)
     }
 
-    // Note: might be a spot for generics, just trait "from_file". Maybe proc_macro
-    // if that doesn't work due to needing an enum type for the channel.
-    pub fn load_wav(&self, file: String, callback_sender: DataResSender<Wav>) {
-        self.req_sender
-            .send(DataReq::Wav((file, callback_sender)))
-            .unwrap();
+    /// Read a file that isn't a `Loadable`, such as a shader, from the packs
+    /// or the directory
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.vfs.read(path)
     }
 
-    pub fn load_map(&self, file: String, callback_sender: DataResSender<Map>) {
-        self.req_sender
-            .send(DataReq::Map((file, callback_sender)))
-            .unwrap();
+    /// A shared handle to the asset, only loaded if there isn't already a
+    /// live handle to it
+    pub fn handle<T>(&self, name: &str) -> Handle<T::Output>
+    where
+        T: Loadable + 'static,
+        T::Output: Send + Sync + 'static,
+    {
+        self.queue_handle::<T>(name, None)
     }
 
-    pub fn load_model(&self, file: String, callback_sender: DataResSender<Model>) {
-        self.req_sender
-            .send(DataReq::Model((file, callback_sender)))
-            .unwrap();
+    /// `handle` reporting its progress to the request. Only a new load is
+    /// followed, one already started by someone else is left to its holder to
+    /// report.
+    pub fn handle_for<T>(&self, request: &LoadRequest, name: &str) -> Handle<T::Output>
+    where
+        T: Loadable + 'static,
+        T::Output: Send + Sync + 'static,
+    {
+        request.set_stage(name, LoadStage::Queued);
+        self.queue_handle::<T>(name, Some(request.clone()))
     }
 
-    pub fn load_material(&self, file: String, callback_sender: DataResSender<Vec<Material>>) {
-        self.req_sender
-            .send(DataReq::Material((file, callback_sender)))
-            .unwrap();
+    fn queue_handle<T>(&self, name: &str, request: Option<LoadRequest>) -> Handle<T::Output>
+    where
+        T: Loadable + 'static,
+        T::Output: Send + Sync + 'static,
+    {
+        let (handle, new) = self.cache.get_or_insert::<T::Output>(name);
+        if !new {
+            return handle;
+        }
+        let slot = handle.downgrade();
+        let Some(location) = self.registry.lock().unwrap().location::<T>(name) else {
+            if let Some(request) = request {
+                request.set_stage(name, LoadStage::Failed);
+            }
+            let error = ResourceError::Unregistered(type_name::<T>(), name.to_string());
+            cache::finish(&slot, Err(error.into()));
+            return handle;
+        };
+        let name = name.to_string();
+        let (vfs, resident) = (self.vfs.clone(), self.resident.clone());
+        let job = Box::new(move || {
+            let path = location.to_string() + &name;
+            let mut result = read::<T>(&vfs, &resident, &path, &name, request.as_ref());
+            // Cancelling the request that started the load doesn't fail it
+            // for the other holders, they get it loaded again without it
+            if request.is_some_and(|r| r.is_cancelled()) && slot.ref_count() > 1 {
+                result = read::<T>(&vfs, &resident, &path, &name, None);
+            }
+            cache::finish(&slot, result)
+        });
+        self.req_sender.send(DataReq::Load(job)).unwrap();
+        handle
     }
 
-    pub fn load_texture(&self, file: String, callback_sender: DataResSender<Texture>) {
-        self.req_sender
-            .send(DataReq::Texture((file, callback_sender)))
-            .unwrap();
+    /// Mark a dependency as held by the system that loads it, or as released.
+    /// Resources that depend on a resident one can skip loading it again.
+    pub fn set_resident<T: Loadable + 'static>(&self, name: &str, resident: bool) {
+        let key = (TypeId::of::<T>(), name.to_string());
+        let mut names = self.resident.lock().unwrap();
+        if resident {
+            names.insert(key);
+        } else {
+            names.remove(&key);
+        }
     }
 
+    /// Requests already sent are finished before the workers stop
     pub fn cleanup(&self) {
-        // TODO: wait for join. Because this is behind an Arc we can't take ownership
-        // of self here. Adding in drop would work but it would be good to keep the
-        // shutdown order explicit with resource manager at the end
-        self.shutdown_sender.send(()).unwrap();
+        // Stopped first so it doesn't queue reloads for stopped workers
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.stop();
+        }
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for _ in &workers {
+            self.req_sender.send(DataReq::Shutdown).unwrap();
+        }
+        for worker in workers {
+            if worker.join().is_err() {
+                warn!("Resource worker panicked");
+            }
+        }
     }
 }
 
-fn run_io(rec: Receiver<DataReq>, shutdown: Receiver<()>) {
+/// The search paths with the packs under each mounted, for tools that read
+/// assets without a `ResourceManager`
+pub fn asset_vfs(search_paths: Vec<PathBuf>) -> Vfs {
+    let mut vfs = Vfs::new(search_paths);
+    vfs.mount_packs(PACK_LOCATION);
+    vfs
+}
+
+/// Set `HOT_RELOAD` to anything but 0 to reload assets when their files change
+fn hot_reload() -> bool {
+    env::var(HOT_RELOAD_ENV).is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+fn run_io(rec: Arc<Mutex<Receiver<DataReq>>>) {
     loop {
-        if shutdown.try_recv().is_ok() {
+        // The lock is only held while waiting so the other workers can take
+        // the next request as soon as this one has its own
+        let req = rec.lock().unwrap().recv();
+        let Ok(req) = req else {
             break;
-        }
-        let Some(req) = rec.try_recv().ok() else {
-            continue;
         };
 
         match req {
-            DataReq::Wav((s, send)) => {
-                load::<Wav>(AUDIO_LOCATION, s, send);
-            }
-            DataReq::Map((s, send)) => {
-                load::<Map>(MAP_LOCATION, s, send);
-            }
-            DataReq::Model((s, send)) => {
-                load::<Model>(MODEL_LOCATION, s, send);
-            }
-            DataReq::Material((s, send)) => {
-                load::<Material>("", s, send);
-            }
-            DataReq::Texture((s, send)) => {
-                load::<Texture>("", s, send);
-            }
+            DataReq::Load(job) => job(),
+            DataReq::Shutdown => break,
         };
     }
 }
 
 enum DataReq {
-    Wav(DataReqBody<Wav>),
-    Map(DataReqBody<Map>),
-    Model(DataReqBody<Model>),
-    Material(DataReqBody<Vec<Material>>),
-    Texture(DataReqBody<Texture>),
+    /// Loads a resource and sends it back to whoever asked for it
+    Load(Box<dyn FnOnce() + Send>),
+    /// Stops the worker that receives it
+    Shutdown,
 }
 
-pub type DataReqBody<T> = (String, DataResSender<T>);
+/// The directory each `Loadable` type is loaded from, keyed by the type and
+/// the extension of the file
+#[derive(Debug, Default)]
+struct LoaderRegistry {
+    locations: HashMap<(TypeId, String), &'static str>,
+}
+
+impl LoaderRegistry {
+    fn register<T: Loadable + 'static>(&mut self, location: &'static str, extensions: &[&str]) {
+        for extension in extensions {
+            self.locations
+                .insert((TypeId::of::<T>(), extension.to_string()), location);
+        }
+    }
+
+    fn location<T: Loadable + 'static>(&self, name: &str) -> Option<&'static str> {
+        let extension = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+        self.locations.get(&(TypeId::of::<T>(), extension)).copied()
+    }
+}
 
 pub type DataResSender<T> = Sender<(String, Result<T>)>;
 
@@ -113,20 +357,292 @@ pub type DataResRec<T> = Receiver<(String, Result<T>)>;
 
 pub trait Loadable {
     type Output;
-    fn load(path: &str) -> Result<Self::Output>;
+    /// Build the resource from the contents of the file at `path`
+    fn parse(path: &str, bytes: Vec<u8>) -> Result<Self::Output>;
+
+    /// Load the other files a resource made of several refers to, called on
+    /// the worker after `parse`
+    fn load_dependencies(
+        _path: &str,
+        output: Self::Output,
+        _dependencies: &Dependencies,
+    ) -> Result<Self::Output> {
+        Ok(output)
+    }
+
+    /// Read straight from the directory, for files that are never packed
+    fn load(path: &str) -> Result<Self::Output> {
+        Self::parse(path, fs::read(path)?)
+    }
+}
+
+type Resident = Mutex<HashSet<(TypeId, String)>>;
+
+/// Reads the files a resource depends on through the same packs and search
+/// paths, reporting them to the same request
+pub struct Dependencies<'a> {
+    vfs: &'a Vfs,
+    resident: &'a Resident,
+    request: Option<&'a LoadRequest>,
+}
+
+impl Dependencies<'_> {
+    /// Dependencies are named by their full path, they are done as soon as
+    /// they are read since they are uploaded with the resource needing them
+    pub fn load<T: Loadable>(&self, path: &str) -> Result<T::Output> {
+        let resource = read::<T>(self.vfs, self.resident, path, path, self.request);
+        if let Some(request) = self.request {
+            let stage = match resource {
+                Ok(_) => LoadStage::Done,
+                Err(_) => LoadStage::Failed,
+            };
+            request.set_stage(path, stage);
+        }
+        resource
+    }
+
+    /// True if the system the resource is for already holds the dependency,
+    /// see `ResourceManager::set_resident`
+    pub fn resident<T: Loadable + 'static>(&self, path: &str) -> bool {
+        let key = (TypeId::of::<T>(), path.to_string());
+        self.resident.lock().unwrap().contains(&key)
+    }
 }
 
 fn load<T: Loadable>(
+    vfs: &Vfs,
+    resident: &Resident,
     resource_location: &str,
     resource_name: String,
     sender: DataResSender<T::Output>,
+    request: Option<&LoadRequest>,
 ) {
     let path: String = resource_location.to_string() + &resource_name;
-    let resource: Result<T::Output> = T::load(&path);
-    sender.send((resource_name, resource)).unwrap();
+    let resource: Result<T::Output> = read::<T>(vfs, resident, &path, &resource_name, request);
+    // The requester may have gone while it was loading
+    if sender.send((resource_name, resource)).is_err() {
+        debug!(path = path, "Loaded resource no longer wanted");
+    }
+}
+
+/// Stages and bytes are reported under the name the asset was requested by
+fn read<T: Loadable>(
+    vfs: &Vfs,
+    resident: &Resident,
+    path: &str,
+    name: &str,
+    request: Option<&LoadRequest>,
+) -> Result<T::Output> {
+    let dependencies = Dependencies {
+        vfs,
+        resident,
+        request,
+    };
+    let Some(request) = request else {
+        let bytes = vfs
+            .read(path)
+            .with_context(|| format!("Reading {}", path))?;
+        let resource = T::parse(path, bytes)?;
+        return T::load_dependencies(path, resource, &dependencies);
+    };
+    let cancelled = || ResourceError::Cancelled(name.to_string());
+    if request.is_cancelled() {
+        return Err(cancelled().into());
+    }
+
+    request.set_stage(name, LoadStage::Read);
+    let bytes = vfs.read_with(path, |read, total| {
+        request.set_bytes(name, read, total);
+        match request.is_cancelled() {
+            true => Err(io::Error::new(io::ErrorKind::Interrupted, cancelled())),
+            false => Ok(()),
+        }
+    });
+    if request.is_cancelled() {
+        return Err(cancelled().into());
+    }
+    let bytes = bytes.with_context(|| format!("Reading {}", path));
+    let resource = bytes.and_then(|bytes| {
+        request.set_stage(name, LoadStage::Decode);
+        let resource = T::parse(path, bytes)?;
+        T::load_dependencies(path, resource, &dependencies)
+    });
+    let stage = match resource {
+        Ok(_) => LoadStage::Upload,
+        Err(_) => LoadStage::Failed,
+    };
+    request.set_stage(name, stage);
+    resource
+}
+
+pub const AUDIO_LOCATION: &str = "assets/sounds/";
+const MAP_LOCATION: &str = "assets/maps/";
+const MODEL_LOCATION: &str = "assets/models/";
+const PACK_LOCATION: &str = "packs/";
+const MAX_WORKERS: usize = 4;
+
+#[derive(Debug)]
+enum ResourceError {
+    Unregistered(&'static str, String),
+    Cancelled(String),
 }
 
-const AUDIO_LOCATION: &'static str = "assets/sounds/";
-const MAP_LOCATION: &'static str = "assets/maps/";
-const MODEL_LOCATION: &'static str = "assets/models/";
+impl Display for ResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unregistered(t, name) => write!(f, "No {} loader registered for {}", t, name),
+            Self::Cancelled(name) => write!(f, "Loading {} was cancelled", name),
+        }
+    }
+}
+
+impl Error for ResourceError {}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::resource::cache::LoadState;
+
+    struct Text;
+
+    impl Loadable for Text {
+        type Output = String;
+        fn parse(_path: &str, bytes: Vec<u8>) -> Result<String> {
+            Ok(String::from_utf8(bytes)?)
+        }
+    }
+
+    // Holds `Gated` loads in parse until it is opened
+    static GATE: AtomicBool = AtomicBool::new(false);
+
+    struct Gated;
+
+    impl Loadable for Gated {
+        type Output = String;
+        fn parse(path: &str, bytes: Vec<u8>) -> Result<String> {
+            while !GATE.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Text::parse(path, bytes)
+        }
+    }
+
+    /// A manager loading `Text` and `Gated` from `text/` in a new directory
+    /// holding `files`
+    fn manager(test: &str, files: &[(&str, &str)]) -> (ResourceManager, PathBuf) {
+        let dir = env::temp_dir().join(format!("resource_{}_{}", test, process::id()));
+        fs::create_dir_all(dir.join("text")).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join("text").join(name), contents).unwrap();
+        }
+        let manager = ResourceManager::new(vec![dir.clone()]);
+        manager.register::<Text>("text/", &["txt"]);
+        manager.register::<Gated>("text/", &["txt"]);
+        (manager, dir)
+    }
+
+    fn finish(manager: ResourceManager, dir: PathBuf) {
+        manager.cleanup();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    fn wait_ready<T>(handle: &Handle<T>) -> Arc<T> {
+        for _ in 0..1000 {
+            match handle.state() {
+                LoadState::Pending => thread::sleep(Duration::from_millis(5)),
+                LoadState::Ready(asset) => return asset,
+                LoadState::Failed(e) => panic!("{:?} failed to load: {}", handle, e),
+            }
+        }
+        panic!("{:?} never loaded", handle);
+    }
+
+    #[test]
+    fn registry_dispatches_by_type_and_extension() {
+        let mut registry = LoaderRegistry::default();
+        registry.register::<Wav>(AUDIO_LOCATION, &["wav"]);
+        registry.register::<Text>("text/", &["txt", "md"]);
+        assert_eq!(registry.location::<Wav>("a.wav"), Some(AUDIO_LOCATION));
+        assert_eq!(registry.location::<Wav>("A.WAV"), Some(AUDIO_LOCATION));
+        assert_eq!(registry.location::<Text>("notes.md"), Some("text/"));
+        assert_eq!(registry.location::<Text>("a.wav"), None);
+        assert_eq!(registry.location::<Wav>("a.txt"), None);
+        assert_eq!(registry.location::<Wav>("no_extension"), None);
+
+        registry.register::<Text>("moved/", &["txt"]);
+        assert_eq!(registry.location::<Text>("a.txt"), Some("moved/"));
+        assert_eq!(registry.location::<Text>("a.md"), Some("text/"));
+    }
+
+    #[test]
+    fn unregistered_loads_fail_without_a_worker() {
+        let (manager, dir) = manager("unregistered", &[]);
+        let request = manager.request();
+        let handle = manager.handle_for::<Text>(&request, "a.png");
+        assert!(matches!(handle.state(), LoadState::Failed(_)));
+        assert_eq!(request.asset("a.png").unwrap().stage, LoadStage::Failed);
 
+        let (sender, receiver) = mpsc::channel();
+        manager.load::<Map>("a.wav".to_string(), sender);
+        let (name, result) = receiver.recv().unwrap();
+        assert_eq!(name, "a.wav");
+        assert!(result.is_err());
+        finish(manager, dir);
+    }
+
+    #[test]
+    fn loads_of_one_name_share_a_handle_until_it_is_dropped() {
+        let (manager, dir) = manager("shared", &[("a.txt", "contents")]);
+        let first = manager.handle::<Text>("a.txt");
+        let second = manager.handle::<Text>("a.txt");
+        assert_eq!(first.ref_count(), 2);
+        assert!(Arc::ptr_eq(&wait_ready(&first), &second.get().unwrap()));
+
+        let weak = first.downgrade();
+        drop(first);
+        drop(second);
+        assert_eq!(weak.ref_count(), 0);
+        let again = manager.handle::<Text>("a.txt");
+        assert_eq!(again.ref_count(), 1);
+        assert_eq!(*wait_ready(&again), "contents");
+        finish(manager, dir);
+    }
+
+    #[test]
+    fn requests_follow_a_load_until_it_is_handed_over() {
+        let (manager, dir) = manager("request", &[("a.txt", "contents")]);
+        let request = manager.request();
+        let handle = manager.handle_for::<Text>(&request, "a.txt");
+        wait_ready(&handle);
+        let asset = request.asset("a.txt").unwrap();
+        assert_eq!(asset.stage, LoadStage::Upload);
+        assert_eq!((asset.bytes_read, asset.bytes_total), (8, 8));
+        assert!(!request.progress().done());
+
+        // Whoever takes the asset marks it done
+        request.set_stage("a.txt", LoadStage::Done);
+        let progress = request.progress();
+        assert!(progress.done());
+        assert_eq!(progress.fraction, 1.0);
+        finish(manager, dir);
+    }
+
+    #[test]
+    fn cancelling_a_request_keeps_a_shared_load_going() {
+        let (manager, dir) = manager("cancel", &[("a.txt", "contents")]);
+        let request = manager.request();
+        let handle = manager.handle_for::<Gated>(&request, "a.txt");
+        let shared = manager.handle::<Gated>("a.txt");
+        request.cancel();
+        GATE.store(true, Ordering::Relaxed);
+
+        assert_eq!(*wait_ready(&shared), "contents");
+        assert!(Arc::ptr_eq(&handle.get().unwrap(), &shared.get().unwrap()));
+        assert_eq!(request.asset("a.txt").unwrap().stage, LoadStage::Cancelled);
+        finish(manager, dir);
+    }
+}
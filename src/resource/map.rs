@@ -1,4 +1,4 @@
-use std::{error::Error, fmt::Display, fs::OpenOptions, io::Read, num::ParseFloatError};
+use std::{error::Error, fmt::Display, num::ParseFloatError};
 
 use anyhow::{Context, Result};
 use tracing::debug;
@@ -10,18 +10,17 @@ pub struct Map {
     pub bpm: f32,
     pub subdivisions: f32,
     pub start_offset: f32,
-    pub music: String,
+    /// Played together in sync, the first stem is the track the others
+    /// follow
+    pub stems: Vec<Stem>,
     pub beats: Vec<(bool, bool, bool)>,
 }
 
 impl Loadable for Map {
     type Output = Self;
-    fn load(file: &str) -> Result<Self> {
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
         debug!(file = file, "Loading Map");
-        let mut file = OpenOptions::new().read(true).open(file)?;
-
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
+        let buf = String::from_utf8(bytes).context("Reading Map")?;
         let mut lines = buf.lines().filter(|l| !l.starts_with("//"));
         let _: u64 = lines
             .next()
@@ -37,18 +36,46 @@ impl Loadable for Map {
             return Err(MapError::BadMetadata(metadata_parts.len()).into());
         }
         let music = lines.next().ok_or(MapError::MissingSongData)?;
+        let stems = music.split(",").map(parse_stem).collect();
         let beats: Vec<(bool, bool, bool)> = lines.map(|s| parse_map_line(s)).collect();
 
         Ok(Map {
             bpm: metadata_parts[0],
             subdivisions: metadata_parts[1],
             start_offset: metadata_parts[2],
-            music: music.to_string(),
+            stems,
             beats,
         })
     }
 }
 
+impl Map {
+    /// The lead stem, the map is timed against this track
+    pub fn music(&self) -> &str {
+        &self.stems[0].wav
+    }
+
+    pub fn stem(&self, name: &str) -> Option<&Stem> {
+        self.stems.iter().find(|s| s.name == name)
+    }
+}
+
+/// One part of the music, written as `<name>=<wav>` or just `<wav>` for a
+/// stem named "music"
+#[derive(Debug, Clone)]
+pub struct Stem {
+    pub name: String,
+    pub wav: String,
+}
+
+fn parse_stem(s: &str) -> Stem {
+    let (name, wav) = s.trim().split_once('=').unwrap_or(("music", s.trim()));
+    Stem {
+        name: name.to_string(),
+        wav: wav.to_string(),
+    }
+}
+
 fn parse_map_line(s: &str) -> (bool, bool, bool) {
     let mut result = [false; 3];
     for i in 0..3 {
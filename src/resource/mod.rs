@@ -1,5 +1,12 @@
 pub mod audio;
+pub mod cache;
 pub mod manager;
 pub mod map;
 pub mod model;
+pub mod pack;
+pub mod progress;
+pub mod scope;
+pub mod settings;
+pub mod vfs;
+pub mod watch;
 
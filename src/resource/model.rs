@@ -1,11 +1,73 @@
-use std::{collections::HashMap, fmt::Debug, fs::OpenOptions, io::Read};
+use std::{collections::HashMap, error::Error, fmt::Debug, fmt::Display};
 
 use anyhow::{Context, Result};
 use image::DynamicImage;
 use tracing::{debug, warn};
 
-use super::manager::Loadable;
+use super::manager::{Dependencies, Loadable};
 
+/// A model with the materials and textures it uses, loaded together on a
+/// worker so the renderer only has to upload them
+#[derive(Debug)]
+pub struct ModelBundle {
+    pub model: Model,
+    /// Each material file the model uses and the materials in it
+    pub material_files: Vec<(String, Vec<Material>)>,
+    pub textures: Vec<Texture>,
+}
+
+impl Loadable for ModelBundle {
+    type Output = Self;
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
+        Ok(ModelBundle {
+            model: Model::parse(file, bytes)?,
+            material_files: Vec::new(),
+            textures: Vec::new(),
+        })
+    }
+
+    /// Every file is tried so the error names all of the ones that failed.
+    /// Textures the renderer already has from another model aren't decoded
+    /// again.
+    fn load_dependencies(
+        file: &str,
+        mut bundle: Self,
+        dependencies: &Dependencies,
+    ) -> Result<Self> {
+        let mut failed = Vec::new();
+        for material_file in &bundle.model.materials {
+            match dependencies.load::<Material>(material_file) {
+                Ok(materials) => bundle
+                    .material_files
+                    .push((material_file.clone(), materials)),
+                Err(e) => failed.push((material_file.clone(), e)),
+            }
+        }
+
+        let mut textures: Vec<String> = bundle
+            .material_files
+            .iter()
+            .flat_map(|(_, materials)| materials)
+            .flat_map(|m| [m.diffuse_map.clone(), m.specular_map.clone()])
+            .collect();
+        textures.sort();
+        textures.dedup();
+        textures.retain(|texture| !dependencies.resident::<Texture>(texture));
+        for texture in textures {
+            match dependencies.load::<Texture>(&texture) {
+                Ok(texture) => bundle.textures.push(texture),
+                Err(e) => failed.push((texture, e)),
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(ModelError::Dependencies(file.to_string(), failed).into());
+        }
+        Ok(bundle)
+    }
+}
+
+#[derive(Debug)]
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<String>,
@@ -33,13 +95,10 @@ impl Debug for Mesh {
 
 impl Loadable for Model {
     type Output = Self;
-    fn load(file: &str) -> Result<Self> {
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
         debug!(file = file, "Load Model");
         let dir = find_dir(file);
-        let mut file = OpenOptions::new().read(true).open(file).unwrap();
-
-        let mut text = String::new();
-        file.read_to_string(&mut text).unwrap();
+        let text = String::from_utf8(bytes).context("Reading Model File")?;
 
         let mut meshes: Vec<Mesh> = Vec::new();
         let mut mesh_name: Option<String> = None;
@@ -199,6 +258,7 @@ struct Index {
     norm: usize,
 }
 
+#[derive(Clone)]
 pub struct Material {
     pub name: String,
     pub ambient: (f32, f32, f32),
@@ -226,15 +286,9 @@ impl Debug for Material {
 
 impl Loadable for Material {
     type Output = Vec<Self>;
-    fn load(filename: &str) -> Result<Vec<Self>> {
+    fn parse(filename: &str, bytes: Vec<u8>) -> Result<Vec<Self>> {
         debug!(file = filename, "Loading Material");
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(filename)
-            .context("Open Material File")?;
-        let mut text = String::new();
-        file.read_to_string(&mut text)
-            .context("Reading Material File")?;
+        let text = String::from_utf8(bytes).context("Reading Material File")?;
 
         let dir = find_dir(filename).to_string();
 
@@ -359,9 +413,9 @@ pub struct Texture {
 
 impl Loadable for Texture {
     type Output = Self;
-    fn load(file: &str) -> Result<Self> {
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
         debug!(file = file, "Loading Texture");
-        let texture = image::open(&file).context("Loading Texture")?;
+        let texture = image::load_from_memory(&bytes).context("Loading Texture")?;
         Ok(Texture {
             name: file.to_string(),
             image: texture,
@@ -369,7 +423,7 @@ impl Loadable for Texture {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum IlluminationModel {
     ColorOnAmbientOff,
     ColorOnAmbientOn,
@@ -410,3 +464,24 @@ fn find_dir(file: &str) -> &str {
         None => "",
     }
 }
+
+#[derive(Debug)]
+enum ModelError {
+    Dependencies(String, Vec<(String, anyhow::Error)>),
+}
+
+impl Display for ModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dependencies(model, failed) => {
+                write!(f, "{} of the files {} uses failed to load", failed.len(), model)?;
+                for (file, e) in failed {
+                    write!(f, "\n  {}: {:#}", file, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ModelError {}
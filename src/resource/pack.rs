@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use tracing::{debug, info};
+
+/// An archive of asset files. The header is the magic, version and entry
+/// count followed by the index, then the entries are stored back to back.
+/// All numbers are little endian.
+///
+/// Each index entry is the name length (u16), the name, the offset of the
+/// entry from the start of the pack (u64), its stored length (u64), its
+/// length once decompressed (u64) and its compression (u8).
+#[derive(Debug)]
+pub struct Pack {
+    path: PathBuf,
+    entries: HashMap<String, PackEntry>,
+}
+
+#[derive(Debug)]
+struct PackEntry {
+    offset: u64,
+    stored_len: u64,
+    len: u64,
+    compression: PackCompression,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PackCompression {
+    None,
+    Deflate,
+}
+
+impl Pack {
+    /// Only the index is read, entries are read from the file as they are
+    /// asked for
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic).context("Reading Pack Header")?;
+        if &magic != PACK_MAGIC {
+            return Err(PackError::BadMagic.into());
+        }
+        let version = read_u32(&mut file)?;
+        if version != PACK_VERSION {
+            return Err(PackError::UnsupportedVersion(version).into());
+        }
+
+        let count = read_u32(&mut file)?;
+        // Not preallocated since a corrupt count could be huge
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let name_len = read_u16(&mut file)?;
+            let mut name = vec![0; name_len as usize];
+            file.read_exact(&mut name).context("Reading Pack Index")?;
+            let name = String::from_utf8(name).context("Reading Pack Entry Name")?;
+            let entry = PackEntry {
+                offset: read_u64(&mut file)?,
+                stored_len: read_u64(&mut file)?,
+                len: read_u64(&mut file)?,
+                compression: match read_u8(&mut file)? {
+                    0 => PackCompression::None,
+                    1 => PackCompression::Deflate,
+                    c => return Err(PackError::UnknownCompression(name, c).into()),
+                },
+            };
+            entries.insert(name, entry);
+        }
+        debug!(pack = path.to_str(), entries = entries.len(), "Opened pack");
+        Ok(Pack {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// A reader of the entry's decompressed contents and their length, None
+    /// if the pack doesn't have it. The pack is opened again for each entry so
+    /// workers can read at the same time.
+    pub fn open_entry(&self, name: &str) -> Option<io::Result<(Box<dyn Read>, u64)>> {
+        let entry = self.entries.get(name)?;
+        Some(self.reader(entry))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    fn reader(&self, entry: &PackEntry) -> io::Result<(Box<dyn Read>, u64)> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let stored = file.take(entry.stored_len);
+        let reader: Box<dyn Read> = match entry.compression {
+            PackCompression::None => Box::new(BufReader::new(stored)),
+            PackCompression::Deflate => Box::new(DeflateDecoder::new(stored)),
+        };
+        Ok((reader, entry.len))
+    }
+}
+
+/// Entries shorter than their index says, or loose files that changed while
+/// being read
+pub fn truncated(name: &str, read: u64, expected: u64) -> io::Error {
+    let error = PackError::EntryTruncated(name.to_string(), read, expected);
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+/// Pack every file under `dir` into `output`. Entries are named by their path
+/// joined onto `dir`, the same path the loaders ask for. Compressed entries
+/// are only kept compressed if that makes them smaller.
+pub fn write_pack(dir: &str, output: &str, compress: bool) -> Result<()> {
+    let dir = dir.trim_end_matches('/');
+    let mut files = Vec::new();
+    find_files(Path::new(dir), &mut files).with_context(|| format!("Reading {}", dir))?;
+    files.sort();
+
+    let mut entries = Vec::with_capacity(files.len());
+    for file in files {
+        let name = file
+            .to_str()
+            .ok_or_else(|| PackError::BadName(file.clone()))?
+            .replace('\\', "/");
+        let name = name.strip_prefix("./").unwrap_or(&name).to_string();
+        if name.len() > u16::MAX as usize {
+            return Err(PackError::BadName(file).into());
+        }
+        let bytes = fs::read(&file).with_context(|| format!("Reading {}", name))?;
+        let len = bytes.len() as u64;
+        let (compression, stored) = if compress {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&bytes)?;
+            let deflated = encoder.finish()?;
+            if deflated.len() < bytes.len() {
+                (PackCompression::Deflate, deflated)
+            } else {
+                (PackCompression::None, bytes)
+            }
+        } else {
+            (PackCompression::None, bytes)
+        };
+        entries.push((name, len, compression, stored));
+    }
+
+    let index_len: usize = entries
+        .iter()
+        .map(|(name, ..)| INDEX_ENTRY_LEN + name.len())
+        .sum();
+    let mut offset = (PACK_HEADER_LEN + index_len) as u64;
+    if let Some(parent) = Path::new(output).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(PACK_MAGIC)?;
+    writer.write_all(&PACK_VERSION.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u32).to_le_bytes())?;
+    for (name, len, compression, stored) in &entries {
+        writer.write_all(&(name.len() as u16).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&(stored.len() as u64).to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&[*compression as u8])?;
+        offset += stored.len() as u64;
+    }
+    for (.., stored) in &entries {
+        writer.write_all(stored)?;
+    }
+    writer.flush()?;
+
+    info!(
+        output = output,
+        entries = entries.len(),
+        bytes = offset,
+        "Pack written"
+    );
+    Ok(())
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut bytes = [0; 1];
+    reader
+        .read_exact(&mut bytes)
+        .context("Reading Pack Index")?;
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    let mut bytes = [0; 2];
+    reader
+        .read_exact(&mut bytes)
+        .context("Reading Pack Index")?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("Reading Pack Index")?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader
+        .read_exact(&mut bytes)
+        .context("Reading Pack Index")?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+const PACK_MAGIC: &[u8; 4] = b"MBPK";
+const PACK_VERSION: u32 = 1;
+// Magic, version and entry count
+const PACK_HEADER_LEN: usize = 12;
+// Everything but the name, its length, offset, lengths and compression
+const INDEX_ENTRY_LEN: usize = 2 + 8 + 8 + 8 + 1;
+
+#[derive(Debug)]
+enum PackError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    UnknownCompression(String, u8),
+    EntryTruncated(String, u64, u64),
+    BadName(PathBuf),
+}
+
+impl Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "Not a pack file"),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported pack version {}", v),
+            Self::UnknownCompression(name, c) => {
+                write!(f, "Unknown compression {} for {}", c, name)
+            }
+            Self::EntryTruncated(name, got, expected) => {
+                write!(f, "{} is {} bytes, expected {}", name, got, expected)
+            }
+            Self::BadName(path) => write!(f, "Can't name pack entry {:?}", path),
+        }
+    }
+}
+
+impl Error for PackError {}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::resource::vfs::Vfs;
+
+    #[test]
+    fn packs_read_back_what_was_written() {
+        let dir = env::temp_dir().join("pack_round_trip");
+        // The pack is mounted from a search path of its own
+        let root = env::temp_dir().join("pack_round_trip_root");
+        let output = root.join("packs/round_trip.pak");
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&dir).unwrap();
+        // Noise doesn't deflate so is stored as is, text does
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let text = "The same line over and over\n".repeat(200).into_bytes();
+        fs::write(dir.join("noise.bin"), &noise).unwrap();
+        fs::write(dir.join("text.txt"), &text).unwrap();
+        write_pack(dir.to_str().unwrap(), output.to_str().unwrap(), true).unwrap();
+        let noise_name = dir.join("noise.bin").to_str().unwrap().to_string();
+        let text_name = dir.join("text.txt").to_str().unwrap().to_string();
+        // Only the pack is left to read from
+        fs::remove_dir_all(&dir).unwrap();
+
+        let pack = Pack::open(&output).unwrap();
+        let compression = |name: &str| pack.entries[name].compression;
+        assert_eq!(compression(&noise_name), PackCompression::None);
+        assert_eq!(compression(&text_name), PackCompression::Deflate);
+        let mount = || {
+            let mut vfs = Vfs::new(vec![root.clone()]);
+            vfs.mount_packs("packs");
+            vfs
+        };
+        let vfs = mount();
+        assert_eq!(vfs.read(&noise_name).unwrap(), noise);
+        assert_eq!(vfs.read(&text_name).unwrap(), text);
+
+        // Cut the pack off halfway through each entry in turn
+        let bytes = fs::read(&output).unwrap();
+        for name in [&noise_name, &text_name] {
+            let entry = &pack.entries[name];
+            let cut = (entry.offset + entry.stored_len / 2) as usize;
+            fs::write(&output, &bytes[..cut]).unwrap();
+            assert!(mount().read(name).is_err(), "{} read when truncated", name);
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
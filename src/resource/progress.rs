@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub u64);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LoadStage {
+    /// Waiting for a worker
+    Queued,
+    Read,
+    Decode,
+    /// Decoded and waiting for the system that asked for it to take it
+    Upload,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl LoadStage {
+    pub fn finished(&self) -> bool {
+        matches!(self, Self::Done | Self::Failed | Self::Cancelled)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AssetProgress {
+    pub stage: LoadStage,
+    pub bytes_read: u64,
+    pub bytes_total: u64,
+}
+
+impl AssetProgress {
+    /// Reading counts for most of an asset, decoding and uploading are
+    /// quick in comparison
+    fn fraction(&self) -> f32 {
+        match self.stage {
+            LoadStage::Queued => 0.0,
+            LoadStage::Read if self.bytes_total == 0 => 0.0,
+            LoadStage::Read => READ_SHARE * self.bytes_read as f32 / self.bytes_total as f32,
+            LoadStage::Decode => READ_SHARE,
+            LoadStage::Upload => UPLOAD_SHARE,
+            LoadStage::Done | LoadStage::Failed | LoadStage::Cancelled => 1.0,
+        }
+    }
+}
+
+/// Totals over every asset in a request
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestProgress {
+    pub assets: usize,
+    pub finished: usize,
+    pub failed: usize,
+    pub bytes_read: u64,
+    pub bytes_total: u64,
+    /// Each asset counts the same however big it is
+    pub fraction: f32,
+}
+
+impl RequestProgress {
+    pub fn done(&self) -> bool {
+        self.finished == self.assets
+    }
+}
+
+/// Groups the loads made for one purpose, such as a level, so their progress
+/// can be followed together and they can be cancelled together. Cloning it
+/// shares the same request.
+#[derive(Clone)]
+pub struct LoadRequest {
+    id: RequestId,
+    state: Arc<RequestState>,
+}
+
+struct RequestState {
+    cancelled: AtomicBool,
+    assets: Mutex<HashMap<String, AssetProgress>>,
+}
+
+impl LoadRequest {
+    pub fn new(id: RequestId) -> Self {
+        LoadRequest {
+            id,
+            state: Arc::new(RequestState {
+                cancelled: AtomicBool::new(false),
+                assets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Loads that haven't started are skipped and reads in progress stop at
+    /// their next chunk. A load shared with another holder through a handle
+    /// is loaded again for them.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+        for asset in self.state.assets.lock().unwrap().values_mut() {
+            if !asset.stage.finished() {
+                asset.stage = LoadStage::Cancelled;
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Starts tracking the asset if it isn't already, a finished asset stays
+    /// finished once cancelled
+    pub fn set_stage(&self, name: &str, stage: LoadStage) {
+        let mut assets = self.state.assets.lock().unwrap();
+        let asset = assets.entry(name.to_string()).or_insert(AssetProgress {
+            stage,
+            bytes_read: 0,
+            bytes_total: 0,
+        });
+        if asset.stage != LoadStage::Cancelled {
+            asset.stage = stage;
+        }
+    }
+
+    pub fn set_bytes(&self, name: &str, bytes_read: u64, bytes_total: u64) {
+        if let Some(asset) = self.state.assets.lock().unwrap().get_mut(name) {
+            asset.bytes_read = bytes_read;
+            asset.bytes_total = bytes_total;
+        }
+    }
+
+    pub fn asset(&self, name: &str) -> Option<AssetProgress> {
+        self.state.assets.lock().unwrap().get(name).copied()
+    }
+
+    pub fn progress(&self) -> RequestProgress {
+        let assets = self.state.assets.lock().unwrap();
+        let mut progress = RequestProgress {
+            assets: assets.len(),
+            ..Default::default()
+        };
+        for asset in assets.values() {
+            progress.finished += asset.stage.finished() as usize;
+            progress.failed += (asset.stage == LoadStage::Failed) as usize;
+            progress.bytes_read += asset.bytes_read;
+            progress.bytes_total += asset.bytes_total;
+            progress.fraction += asset.fraction();
+        }
+        if !assets.is_empty() {
+            progress.fraction /= assets.len() as f32;
+        }
+        progress
+    }
+}
+
+impl Debug for LoadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let progress = self.progress();
+        write!(
+            f,
+            "LoadRequest({:?}, {}/{} assets{})",
+            self.id.0,
+            progress.finished,
+            progress.assets,
+            if self.is_cancelled() {
+                ", cancelled"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+const READ_SHARE: f32 = 0.8;
+const UPLOAD_SHARE: f32 = 0.9;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_follows_each_asset_to_done() {
+        let request = LoadRequest::new(RequestId(0));
+        request.set_stage("a", LoadStage::Queued);
+        request.set_stage("b", LoadStage::Queued);
+        assert_eq!(request.progress().fraction, 0.0);
+
+        request.set_stage("a", LoadStage::Read);
+        request.set_bytes("a", 50, 100);
+        let progress = request.progress();
+        assert_eq!((progress.bytes_read, progress.bytes_total), (50, 100));
+        assert_eq!(progress.fraction, READ_SHARE / 2.0 / 2.0);
+
+        for stage in [LoadStage::Decode, LoadStage::Upload, LoadStage::Done] {
+            request.set_stage("a", stage);
+        }
+        request.set_stage("b", LoadStage::Failed);
+        let progress = request.progress();
+        assert!(progress.done());
+        assert_eq!((progress.finished, progress.failed), (2, 1));
+        assert_eq!(progress.fraction, 1.0);
+    }
+
+    #[test]
+    fn cancelling_stops_what_is_left_and_keeps_what_finished() {
+        let request = LoadRequest::new(RequestId(0));
+        request.set_stage("done", LoadStage::Done);
+        request.set_stage("reading", LoadStage::Read);
+        request.clone().cancel();
+        assert!(request.is_cancelled());
+        let stage = |name| request.asset(name).unwrap().stage;
+        assert_eq!(stage("done"), LoadStage::Done);
+        assert_eq!(stage("reading"), LoadStage::Cancelled);
+
+        // A worker finishing after the cancel doesn't bring it back
+        request.set_stage("reading", LoadStage::Upload);
+        assert_eq!(stage("reading"), LoadStage::Cancelled);
+        assert!(request.progress().done());
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+/// Tags each resource request with the scope that was open when it was
+/// made. The bottom scope is global and can't be closed, scenes open a scope
+/// on top of it and everything requested until it closes is released with it.
+#[derive(Debug)]
+pub struct ScopeStack {
+    // How many times each resource was requested in each scope
+    scopes: Vec<HashMap<String, usize>>,
+}
+
+impl ScopeStack {
+    pub fn new() -> Self {
+        ScopeStack {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    pub fn open(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// The requests made in the closed scope that haven't been removed, None
+    /// if only the global scope is open
+    pub fn close(&mut self) -> Option<HashMap<String, usize>> {
+        if self.scopes.len() == 1 {
+            return None;
+        }
+        self.scopes.pop()
+    }
+
+    pub fn add(&mut self, name: &str) {
+        let scope = self.scopes.last_mut().unwrap();
+        *scope.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    /// Untag a request from the innermost scope that made one, false if no
+    /// open scope requested it
+    pub fn remove(&mut self, name: &str) -> bool {
+        for scope in self.scopes.iter_mut().rev() {
+            let Some(count) = scope.get_mut(name) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                scope.remove(name);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// True if any open scope, including the global one, holds the resource
+    pub fn contains(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_a_scope_leaves_global_requests_held() {
+        let mut scopes = ScopeStack::new();
+        scopes.add("shared");
+        scopes.open();
+        scopes.add("shared");
+        scopes.add("shared");
+        scopes.add("level");
+        scopes.add("removed");
+        assert!(scopes.remove("removed"));
+
+        let closed = scopes.close().unwrap();
+        let expected = HashMap::from([("shared".to_string(), 2), ("level".to_string(), 1)]);
+        assert_eq!(closed, expected);
+        assert!(scopes.contains("shared"));
+        assert!(!scopes.contains("level"));
+        assert!(scopes.close().is_none());
+    }
+
+    #[test]
+    fn removes_come_from_the_innermost_scope_that_asked() {
+        let mut scopes = ScopeStack::new();
+        scopes.add("global");
+        scopes.open();
+        assert!(scopes.remove("global"));
+        assert!(!scopes.contains("global"));
+        assert!(!scopes.remove("global"));
+        assert_eq!(scopes.close().unwrap(), HashMap::new());
+    }
+}
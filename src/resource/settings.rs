@@ -0,0 +1,121 @@
+use std::{
+    env,
+    error::Error,
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+
+use crate::config::{SETTINGS_FILE, USER_DATA_DIR};
+
+use super::manager::Loadable;
+
+/// Player settings that persist between runs. Stored as `<name> <value>`
+/// lines.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Settings {
+    /// Seconds between the game playing a sound and the player hearing it
+    pub audio_offset: f32,
+    /// Seconds between the game drawing a frame and the player seeing it
+    pub visual_offset: f32,
+}
+
+impl Loadable for Settings {
+    type Output = Self;
+    fn parse(file: &str, bytes: Vec<u8>) -> Result<Self> {
+        debug!(file = file, "Loading Settings");
+        let buf = String::from_utf8(bytes)?;
+
+        let mut settings = Settings::default();
+        let lines = buf
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.starts_with("//") && !l.trim().is_empty());
+        for (i, line) in lines {
+            let Some((name, value)) = line.split_once(' ') else {
+                return Err(SettingsError::MissingValue(i + 1).into());
+            };
+            let value: f32 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("Parsing {}", name))?;
+            match name {
+                "audio_offset" => settings.audio_offset = value,
+                "visual_offset" => settings.visual_offset = value,
+                _ => warn!(name = name, "Unknown setting"),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+impl Settings {
+    /// Falls back to the defaults if there are no saved settings in
+    /// `user_data`
+    pub fn load_or_default(user_data: &Path) -> Self {
+        let file = user_data.join(SETTINGS_FILE);
+        match Self::load(&file.to_string_lossy()) {
+            Ok(settings) => settings,
+            Err(e) => {
+                debug!(err = e.to_string(), "Using default settings");
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save(&self, user_data: &Path) -> Result<()> {
+        fs::create_dir_all(user_data)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(user_data.join(SETTINGS_FILE))?;
+        writeln!(file, "audio_offset {}", self.audio_offset)?;
+        writeln!(file, "visual_offset {}", self.visual_offset)?;
+        Ok(())
+    }
+}
+
+/// Where files the player makes are kept: `%APPDATA%` on Windows,
+/// `~/Library/Application Support` on macOS and `$XDG_DATA_HOME` or
+/// `~/.local/share` elsewhere, each with `USER_DATA_DIR` under it. The working
+/// directory is only used if none of those are set.
+pub fn user_data_dir() -> PathBuf {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".local/share")))
+    };
+    match base {
+        Some(base) => base.join(USER_DATA_DIR),
+        None => {
+            warn!("No user data directory, keeping settings in the working directory");
+            PathBuf::new()
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SettingsError {
+    MissingValue(usize),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingValue(l) => write!(f, "Missing value on line {}", l),
+        }
+    }
+}
+
+impl Error for SettingsError {}
@@ -0,0 +1,223 @@
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, warn};
+
+use crate::config::ASSET_PATH_ENV;
+
+use super::pack::{self, Pack};
+
+/// Reads files from the search paths, later paths shadowing earlier ones.
+/// Each search path's packs are read before its loose files and packs mounted
+/// later shadow earlier ones, so a mod's pack can replace any file of the
+/// game's.
+#[derive(Debug)]
+pub struct Vfs {
+    roots: Vec<Root>,
+}
+
+#[derive(Debug)]
+struct Root {
+    dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+/// Where a path is read from
+enum Source<'a> {
+    Pack(&'a Pack),
+    File(PathBuf),
+}
+
+impl Vfs {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        debug!(roots = format!("{:?}", roots), "Asset search paths");
+        let roots = roots
+            .into_iter()
+            .map(|dir| Root {
+                dir,
+                packs: Vec::new(),
+            })
+            .collect();
+        Vfs { roots }
+    }
+
+    /// Mount the packs in `dir` under every search path
+    pub fn mount_packs(&mut self, dir: &str) {
+        for root in &mut self.roots {
+            let dir = root.dir.join(dir);
+            root.mount_dir(&dir);
+        }
+    }
+
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.read_with(path, |_, _| Ok(()))
+    }
+
+    /// Read in chunks, calling `on_chunk` with the bytes read so far and the
+    /// total after each one. An error from it stops the read.
+    pub fn read_with(
+        &self,
+        path: &str,
+        mut on_chunk: impl FnMut(u64, u64) -> io::Result<()>,
+    ) -> io::Result<Vec<u8>> {
+        let (mut reader, total) = self.open(path)?;
+        // The total can come from a mod's pack index, so isn't trusted with
+        // an allocation that could fail
+        let mut bytes = Vec::with_capacity(total.min(MAX_PREALLOCATE) as usize);
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        on_chunk(0, total)?;
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            bytes.extend_from_slice(&chunk[..n]);
+            on_chunk(bytes.len() as u64, total)?;
+        }
+        if bytes.len() as u64 != total {
+            return Err(pack::truncated(path, bytes.len() as u64, total));
+        }
+        Ok(bytes)
+    }
+
+    fn open(&self, path: &str) -> io::Result<(Box<dyn Read>, u64)> {
+        let source = self.source(path);
+        if let Some(Source::Pack(pack)) = source {
+            if let Some(entry) = pack.open_entry(path) {
+                return entry;
+            }
+        }
+        let Some(Source::File(file)) = source else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found in any search path", path),
+            ));
+        };
+        let file = File::open(file)?;
+        let total = file.metadata()?.len();
+        Ok((Box::new(file), total))
+    }
+
+    /// The newest search path with the file decides where it is read from,
+    /// checking its packs newest first and then its loose files
+    fn source(&self, path: &str) -> Option<Source<'_>> {
+        for root in self.roots.iter().rev() {
+            if let Some(pack) = root.packs.iter().rev().find(|pack| pack.has(path)) {
+                return Some(Source::Pack(pack));
+            }
+            let file = root.dir.join(path);
+            if file.is_file() {
+                return Some(Source::File(file));
+            }
+        }
+        None
+    }
+
+    /// True if the path is read from a pack, even if there is also a loose
+    /// file under an earlier search path
+    pub fn packed(&self, path: &str) -> bool {
+        matches!(self.source(path), Some(Source::Pack(_)))
+    }
+
+    /// The files directly in `dir` under any search path, packed or loose,
+    /// in name order. `dir` ends in a slash like the asset locations.
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let in_dir = |name: &str| name.strip_prefix(dir).is_some_and(|n| !n.contains('/'));
+        let mut names = BTreeSet::new();
+        for root in &self.roots {
+            for pack in &root.packs {
+                names.extend(pack.names().filter(|n| in_dir(n)).map(str::to_string));
+            }
+            let Ok(entries) = fs::read_dir(root.dir.join(dir)) else {
+                continue;
+            };
+            let files = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                .map(|entry| format!("{}{}", dir, entry.file_name().to_string_lossy()));
+            names.extend(files);
+        }
+        names.into_iter().collect()
+    }
+
+    /// The loose file a path is read from, None if a pack shadows it
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        match self.source(path) {
+            Some(Source::File(file)) => Some(file),
+            _ => None,
+        }
+    }
+}
+
+impl Root {
+    /// Mount every `.pak` in the directory in name order, a missing
+    /// directory just means there are no packs
+    fn mount_dir(&mut self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            debug!(dir = dir.to_str(), "No packs to mount");
+            return;
+        };
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == "pak"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            self.mount(&path);
+        }
+    }
+
+    /// A pack that can't be opened is skipped so the rest still load
+    fn mount(&mut self, path: &Path) {
+        match Pack::open(path) {
+            Ok(pack) => self.packs.push(pack),
+            Err(e) => warn!(
+                err = format!("{:#}", e),
+                pack = path.to_str(),
+                "Failed to mount pack"
+            ),
+        }
+    }
+}
+
+/// Where assets are looked for, later paths override earlier ones: the
+/// executable's directory, the working directory, each path in `ASSET_PATH`
+/// then each path passed with `--assets`
+pub fn search_paths(flags: Vec<PathBuf>) -> Vec<PathBuf> {
+    let exe_dir = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    let env_paths: Vec<PathBuf> = env::var_os(ASSET_PATH_ENV)
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    for path in env_paths.iter().chain(&flags) {
+        if !path.is_dir() {
+            warn!(path = path.to_str(), "Asset search path is not a directory");
+        }
+    }
+
+    let candidates = exe_dir
+        .into_iter()
+        .chain(env::current_dir().ok())
+        .chain(env_paths)
+        .chain(flags);
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for root in candidates {
+        // The same directory twice would mount its packs twice, it keeps the
+        // later position
+        let root = fs::canonicalize(&root).unwrap_or(root);
+        roots.retain(|r| *r != root);
+        roots.push(root);
+    }
+    roots
+}
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_PREALLOCATE: u64 = 16 * 1024 * 1024;
@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, info};
+
+/// Polls the modification time of watched files on its own thread and calls
+/// their callback when one changes. Only used in development, polling keeps
+/// it working the same on every platform without another dependency.
+/// Callbacks are called without the files locked, so they may watch files
+/// themselves.
+pub struct Watcher {
+    files: Arc<Mutex<HashMap<String, WatchedFile>>>,
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+struct WatchedFile {
+    modified: Option<SystemTime>,
+    on_change: OnChange,
+}
+
+type OnChange = Arc<dyn Fn() + Send + Sync>;
+
+impl Watcher {
+    pub fn new() -> Self {
+        let files = Arc::new(Mutex::new(HashMap::<String, WatchedFile>::new()));
+        let (stop, stop_rec) = mpsc::channel::<()>();
+        let thread = {
+            let files = files.clone();
+            // Waiting on the stop channel doubles as the sleep between polls
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stop_rec.recv_timeout(WATCH_INTERVAL) {
+                    let mut changed: Vec<OnChange> = Vec::new();
+                    for (path, file) in files.lock().unwrap().iter_mut() {
+                        let modified = modified(path);
+                        if modified.is_some() && modified != file.modified {
+                            info!(path = path, "Reloading changed file");
+                            file.modified = modified;
+                            changed.push(file.on_change.clone());
+                        }
+                    }
+                    for on_change in changed {
+                        on_change();
+                    }
+                }
+            })
+        };
+        Watcher {
+            files,
+            stop,
+            thread,
+        }
+    }
+
+    /// Watching a path again replaces its callback, so the latest owner is
+    /// the one that gets the new version
+    pub fn watch(&self, path: String, on_change: OnChange) {
+        debug!(path = path, "Watching file");
+        let file = WatchedFile {
+            modified: modified(&path),
+            on_change,
+        };
+        self.files.lock().unwrap().insert(path, file);
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+impl Debug for Watcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Watcher({} files)", self.files.lock().unwrap().len())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
@@ -1,8 +1,7 @@
 use std::error::Error;
 use std::ffi::CString;
 use std::fmt::Display;
-use std::fs::OpenOptions;
-use std::io::{Error as IoError, Read};
+use std::io::Error as IoError;
 use std::path::PathBuf;
 use std::str::Utf8Error;
 use std::{ptr, str};
@@ -11,21 +10,21 @@ use tracing::error;
 
 use gl::types::*;
 
+use crate::resource::manager::ResourceManager;
+
+/// Shaders are read through the resource manager so they can come from a
+/// pack
 pub unsafe fn create_shader(
+    resource_manager: &ResourceManager,
     shader_path: &PathBuf,
     shader_type: GLenum,
 ) -> Result<u32, OpenGLError> {
-    let mut file = match OpenOptions::new().read(true).write(false).open(shader_path) {
-        Ok(f) => f,
+    let path = shader_path.to_str().unwrap_or("Invalid Unicode");
+    let shader_bytes = match resource_manager.read(path) {
+        Ok(bytes) => bytes,
         Err(err) => return Err(OpenGLError::FailedToReadShader(err)),
     };
 
-    let mut shader_bytes: Vec<u8> = Vec::new();
-    match file.read_to_end(&mut shader_bytes) {
-        Ok(_) => (),
-        Err(err) => return Err(OpenGLError::FailedToReadShader(err)),
-    }
-
     let shader = gl::CreateShader(shader_type);
     let vertex_str = CString::new(shader_bytes).unwrap();
     gl::ShaderSource(shader, 1, &vertex_str.as_ptr(), ptr::null());
@@ -46,7 +45,6 @@ pub unsafe fn create_shader(
             ptr::null_mut(),
             error_buffer.as_mut_ptr() as *mut GLchar,
         );
-        let path = shader_path.to_str().unwrap_or("Invalid Unicode");
         let message = match str::from_utf8(&error_buffer) {
             Ok(m) => m,
             Err(e) => return Err(OpenGLError::FailedToReadFailToCompileError(e)),
@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
@@ -6,7 +7,7 @@ use std::time::Duration;
 use glfw::{Context, Window};
 
 use super::scenes::SceneManager;
-use crate::audio::AudioManager;
+use crate::audio::{AudioManager, OutputKind};
 use crate::controller::{Button, Controller};
 use crate::render::Renderer;
 use crate::resource::manager::ResourceManager;
@@ -22,7 +23,7 @@ pub struct Game {
 }
 
 impl Game {
-    pub fn new() -> Self {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
         // Window Setup
         let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
         glfw.window_hint(glfw::WindowHint::ContextVersion(3, 3));
@@ -54,10 +55,11 @@ impl Game {
 
         // Setup managers
         let controller = Controller::new(glfw, window_events);
-        let resource_manager = Arc::new(ResourceManager::new());
+        let resource_manager = Arc::new(ResourceManager::new(search_paths));
 
         let (audio_send, audio_rec) = mpsc::channel();
-        let audio_manager = AudioManager::new(resource_manager.clone(), audio_rec);
+        let audio_manager =
+            AudioManager::new(resource_manager.clone(), audio_rec, OutputKind::from_config());
 
         let (render_send, render_rec) = mpsc::channel();
         let renderer = Renderer::new(resource_manager.clone(), render_rec);
@@ -89,14 +91,11 @@ impl Game {
             self.window.set_should_close(true);
             return false;
         }
-        self.scene_manager.update(
-            &delta_time,
-            &self.controller,
-            &self.audio_manager,
-            &self.renderer,
-        );
+        self.scene_manager
+            .update(&delta_time, &self.controller, &self.audio_manager);
 
         // Audio
+        self.audio_manager.advance(delta_time);
         self.audio_manager.update();
 
         // Render
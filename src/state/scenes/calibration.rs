@@ -0,0 +1,162 @@
+use std::sync::mpsc::Sender;
+
+use na::Matrix4;
+use tracing::debug;
+
+use crate::{
+    audio::{AudioMessage, ClickTrack, TrackAction},
+    config::{CALIBRATION_BPM, CALIBRATION_TAPS, CALIBRATION_WARMUP_BEATS},
+    controller::{Button, Controller},
+    resource::settings::Settings,
+};
+
+use super::{Transform, UiElement};
+
+/// Measures latency by having the player tap along to clicks and then to
+/// flashes. How late the taps are relative to when each beat was produced
+/// gives the audio and visual offsets.
+pub struct CalibrationState {
+    stage: Stage,
+    // Controller time the beats of the current stage count from
+    start: Option<f64>,
+    offsets: Vec<f64>,
+    audio_offset: f32,
+    flash: bool,
+    audio_sender: Sender<AudioMessage>,
+    pub result: Option<Settings>,
+    pub menu: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Audio,
+    Visual,
+}
+
+impl CalibrationState {
+    pub fn new(audio_sender: Sender<AudioMessage>) -> Self {
+        CalibrationState {
+            stage: Stage::Audio,
+            start: None,
+            offsets: Vec::with_capacity(CALIBRATION_TAPS),
+            audio_offset: 0.0,
+            flash: false,
+            audio_sender,
+            result: None,
+            menu: false,
+        }
+    }
+
+    pub fn update(&mut self, controller: &Controller) {
+        if controller.buttons().contains(&Button::Quit) {
+            self.set_clicks(false);
+            self.menu = true;
+            return;
+        }
+
+        let beat_seconds = 60.0 / CALIBRATION_BPM;
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.set_clicks(self.stage == Stage::Audio);
+                *self.start.insert(controller.time())
+            }
+        };
+
+        // Taps are matched to the closest beat, the first few are ignored
+        // while the player finds the rhythm
+        for tap in controller.taps() {
+            let beat = (tap - start) / beat_seconds;
+            if beat.round() < CALIBRATION_WARMUP_BEATS {
+                continue;
+            }
+            self.offsets.push((beat - beat.round()) * beat_seconds);
+        }
+
+        let beat = (controller.time() - start) / beat_seconds;
+        let flash_beats = 0.2;
+        self.flash = self.stage == Stage::Visual && beat >= 1.0 && beat.fract() < flash_beats;
+
+        if self.offsets.len() < CALIBRATION_TAPS {
+            return;
+        }
+        let offset = median(&mut self.offsets) as f32;
+        debug!(
+            stage = format!("{:?}", self.stage),
+            offset = offset,
+            "Calibrated"
+        );
+        self.offsets.clear();
+        self.start = None;
+        match self.stage {
+            Stage::Audio => {
+                self.set_clicks(false);
+                self.audio_offset = offset;
+                self.stage = Stage::Visual;
+            }
+            Stage::Visual => {
+                self.result = Some(Settings {
+                    audio_offset: self.audio_offset,
+                    visual_offset: offset,
+                });
+            }
+        }
+    }
+
+    fn set_clicks(&self, on: bool) {
+        let click_track = on.then_some(ClickTrack {
+            track: None,
+            bpm: CALIBRATION_BPM,
+            subdivisions: 1.0,
+            start_offset: 0.0,
+        });
+        let action = TrackAction::ClickTrack(click_track);
+        self.audio_sender
+            .send(AudioMessage::TrackAction(action))
+            .unwrap();
+    }
+
+    pub fn get_ui_elements(&self) -> Vec<UiElement> {
+        // Blue while listening, yellow while watching
+        let stage_color = match self.stage {
+            Stage::Audio => (0.1, 0.4, 0.9),
+            Stage::Visual => (0.9, 0.8, 0.1),
+        };
+        let flash_color = if self.flash {
+            (1.0, 1.0, 1.0)
+        } else {
+            (0.1, 0.1, 0.1)
+        };
+        vec![
+            UiElement {
+                transform: Transform {
+                    position: (-0.2, 0.0, 0.0).into(),
+                    scale: (0.4, 0.4, 1.0).into(),
+                    rotation: Matrix4::identity(),
+                },
+                base_color: flash_color,
+                progress_color: (0.0, 0.0, 0.0),
+                progress: 0.0,
+                merge_color: (0.0, 0.0, 0.0),
+                merge_amount: 0.0,
+            },
+            UiElement {
+                transform: Transform {
+                    position: (-0.5, -0.5, 0.0).into(),
+                    scale: (1.0, 0.1, 1.0).into(),
+                    rotation: Matrix4::identity(),
+                },
+                base_color: (0.1, 0.1, 0.1),
+                progress_color: stage_color,
+                progress: self.offsets.len() as f32 / CALIBRATION_TAPS as f32,
+                merge_color: (0.0, 0.0, 0.0),
+                merge_amount: 0.0,
+            },
+        ]
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+}
@@ -1,20 +1,29 @@
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 
-use na::{vector, Matrix4};
+use na::{vector, Matrix4, Vector3};
+use tracing::{error, info};
 
-use crate::audio::{AudioMessage, TrackAction};
+use crate::audio::{
+    Analysis, AudioMessage, Automation, Bus, ClickTrack, EffectParam, EffectTarget, Listener,
+    PlaybackRate, SongTime, TrackAction, TrackEvent,
+};
 use crate::camera::Camera;
 use crate::config::{
-    self, BACKPACK_MODEL, BEAT_SIZE, COLUMN_WIDTH, CUBE_MODEL, DEATH_TRACK, PLANE_LENGTH,
-    PLANE_MODEL, PLANE_WIDTH,
+    self, BACKPACK_MODEL, BEAT_SIZE, COLUMN_WIDTH, CUBE_MODEL, DEATH_TRACK, DROPPED_STEM,
+    LIGHT_PULSE_CUTOFF, LIGHT_PULSE_STRENGTH, LIGHT_STRENGTH, OBSTACLE_TICK_DISTANCE,
+    OBSTACLE_TICK_LOOKAHEAD, OPEN_CUTOFF, PAUSE_MUFFLE_CUTOFF, PAUSE_MUFFLE_EFFECT,
+    PAUSE_MUFFLE_RAMP, PLANE_LENGTH, PLANE_MODEL, PLANE_WIDTH, PRACTICE_RATE, STEM_RETURN_STREAK,
+    TAPE_STOP_SECONDS,
 };
 use crate::controller::{Button, Controller};
 use crate::physics::AABBColider;
+use crate::resource::manager::DataResRec;
 use crate::resource::map::Map;
+use crate::resource::settings::Settings;
 use crate::shader::DirLight;
 
-use super::{GameObject, Plane, Player, PointLight, Transform};
+use super::{GameObject, Plane, Player, PointLight, Transform, UiElement};
 
 #[derive(Debug)]
 pub struct SceneState {
@@ -23,21 +32,47 @@ pub struct SceneState {
     pub dir_lights: Vec<DirLight>,
     pub player: Player,
     speed: f32,
+    playback_rate: f32,
+    // Where the music should be, used to resync it after a pause
+    song_time: f64,
+    metronome: bool,
+    music_level: f32,
+    // Seconds the cubes are held back so they reach the player when the
+    // beat is heard rather than when it is played
+    scroll_delay: f32,
+    // Seconds the player is behind what is drawn when they react
+    hit_delay: f32,
+    // Only sent to the mixer when the camera moves
+    listener: Option<Listener>,
+    // Obstacles passed since the player was last hit
+    streak: u32,
+    stem_dropped: bool,
     pub plane: Plane,
     pub camera: Camera,
     map: Map,
+    // Only sent to when hot reloading is turned on
+    map_reloads: DataResRec<Map>,
     paused: bool,
     player_state: PlayerStatus,
     audio_sender: Sender<AudioMessage>,
     pub change_scene: Option<usize>,
     pub menu: bool,
+    pub finished: bool,
+    pub deaths: u32,
 }
 
 impl SceneState {
-    pub fn new(map: Map, audio_sender: Sender<AudioMessage>) -> Self {
+    pub fn new(
+        map: Map,
+        map_reloads: DataResRec<Map>,
+        audio_sender: Sender<AudioMessage>,
+        settings: &Settings,
+    ) -> Self {
         let camera = Camera::new(8.0, 0.0, -0.82, vector![0.0, 0.0, 0.0]);
 
-        let cubes = Self::starting_cubes(&map);
+        let speed = BEAT_SIZE * (map.bpm / 60.0) * map.subdivisions;
+        let scroll_delay = settings.audio_offset - settings.visual_offset;
+        let cubes = Self::starting_cubes(&map, speed * scroll_delay);
         let lights = Self::starting_lights();
         
 
@@ -63,7 +98,16 @@ impl SceneState {
                     model: BACKPACK_MODEL.to_string(),
                 },
             },
-            speed: BEAT_SIZE * (map.bpm / 60.0) * map.subdivisions,
+            speed,
+            playback_rate: 1.0,
+            song_time: 0.0,
+            metronome: false,
+            music_level: 0.0,
+            scroll_delay,
+            hit_delay: settings.visual_offset,
+            listener: None,
+            streak: 0,
+            stem_dropped: false,
             plane: Plane {
                 models: [
                     GameObject {
@@ -93,18 +137,28 @@ impl SceneState {
                 ],
             },
             map,
+            map_reloads,
             player_state: PlayerStatus::Alive,
             audio_sender,
             paused: false,
             change_scene: None,
             menu: false,
+            finished: false,
+            deaths: 0,
         };
 
+        scene.attach_stems();
         scene.reset();
         scene
     }
 
     pub fn update(&mut self, delta_time: &Duration, controller: &Controller) {
+        while let Ok((name, map)) = self.map_reloads.try_recv() {
+            match map {
+                Ok(map) => self.reload_map(map),
+                Err(e) => error!(err = e.to_string(), map = name, "Failed to reload map"),
+            }
+        }
         if controller.buttons().contains(&Button::Quit) {
             self.escape();
         }
@@ -116,12 +170,81 @@ impl SceneState {
                 PlayerStatus::Dead => self.dead_update(delta_time, &controller),
             }
         }
+        self.update_listener();
+    }
+
+    fn update_listener(&mut self) {
+        let (x, y, z) = self.camera.position();
+        let listener = Listener {
+            position: vector![x, y, z],
+            right: self.camera.right(),
+        };
+        if self.listener == Some(listener) {
+            return;
+        }
+        self.listener = Some(listener);
+        let action = TrackAction::Listener(listener);
+        let message = AudioMessage::TrackAction(action);
+        self.audio_sender.send(message).unwrap();
+    }
+
+    pub fn track_events(&mut self, events: &[TrackEvent]) {
+        // Playing again seeks the music back to where the cubes are, so an
+        // end reached under the pause muffle is heard again later
+        if self.paused {
+            return;
+        }
+        for event in events {
+            match (event, &self.player_state) {
+                (TrackEvent::Finished(track), PlayerStatus::Alive) if track == self.map.music() => {
+                    self.finished = true;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Pulse the lights with the bass of the music that is actually playing
+    pub fn music_analysis(&mut self, analysis: Option<&Analysis>) {
+        let Some(analysis) = analysis else {
+            return;
+        };
+        let music = analysis.bus(Bus::Music);
+        let bass_bins = (LIGHT_PULSE_CUTOFF / analysis.bin_hz).ceil() as usize;
+        let bass = music
+            .spectrum
+            .iter()
+            .take(bass_bins)
+            .fold(0.0, |a, b| b.max(a));
+        for light in &mut self.point_lights {
+            light.strength = LIGHT_STRENGTH + LIGHT_PULSE_STRENGTH * bass;
+        }
+        self.music_level = music.rms;
+    }
+
+    pub fn get_ui_elements(&self) -> Vec<UiElement> {
+        // Level meter in the top left corner
+        let full_scale_rms = 0.5;
+        vec![UiElement {
+            transform: Transform {
+                position: (-0.95, 0.9, 0.0).into(),
+                scale: (0.3, 0.03, 1.0).into(),
+                rotation: Matrix4::identity(),
+            },
+            base_color: (0.1, 0.1, 0.1),
+            progress_color: (1.0, 1.0, 0.75),
+            progress: f32::min(self.music_level / full_scale_rms, 1.0),
+            merge_color: (0.0, 0.0, 0.0),
+            merge_amount: 0.0,
+        }]
     }
 
     fn alive_update(&mut self, delta_time: &Duration, controller: &Controller) {
         // timing properties
         let dt = delta_time.as_secs_f32();
         let displacement = config::MOVE_SPEED * dt;
+        let speed = self.speed * self.playback_rate;
+        self.song_time += (dt * self.playback_rate) as f64;
 
         // controller input
         let x = controller.direction();
@@ -131,12 +254,39 @@ impl SceneState {
             if light.transform.position.z > 50.0 {
                 light.transform.position.z = -90.0;
             }
-            light.transform.position.z += self.speed * dt;
+            light.transform.position.z += speed * dt;
         }
 
-        // cubes update
+        // cubes update, ticking from their lane as they get close. The tick
+        // is scheduled a little ahead so it lands on the song time it is due
+        // rather than on the frame the cube got there.
+        let tick_z = -OBSTACLE_TICK_DISTANCE;
+        let schedule_z = tick_z - self.speed * OBSTACLE_TICK_LOOKAHEAD;
+        let mut passed = 0;
         for cube in &mut self.cubes {
-            cube.transform.position.z += self.speed * dt;
+            let position = &mut cube.transform.position;
+            let was_far = position.z < schedule_z;
+            let was_ahead = position.z < 0.0;
+            position.z += speed * dt;
+            if was_ahead && position.z >= 0.0 {
+                passed += 1;
+            }
+            if was_far && position.z >= schedule_z {
+                let tick = TrackAction::Tick(Vector3::new(position.x, position.y, tick_z));
+                let at = SongTime {
+                    track: self.map.music().to_string(),
+                    time: self.song_time + ((tick_z - position.z) / self.speed) as f64,
+                };
+                let action = TrackAction::Schedule(at, Box::new(tick));
+                self.audio_sender
+                    .send(AudioMessage::TrackAction(action))
+                    .unwrap();
+            }
+        }
+
+        self.streak += passed;
+        if self.stem_dropped && self.streak >= STEM_RETURN_STREAK {
+            self.mute_stem(DROPPED_STEM, false);
         }
 
         // player update
@@ -170,11 +320,15 @@ impl SceneState {
         }
 
         // plane update
-        self.plane.displace(self.speed * dt);
+        self.plane.displace(speed * dt);
 
         // Check collisions
+        // Check against where the cubes were when the frame the player is
+        // reacting to was drawn
+        let mut hit_position = self.player.model.transform.position;
+        hit_position.z += speed * self.hit_delay;
         let player_collider = AABBColider {
-            position: self.player.model.transform.position,
+            position: hit_position,
             scale: self.player.model.transform.scale,
         };
         for cube in &self.cubes {
@@ -183,8 +337,10 @@ impl SceneState {
                 scale: cube.transform.scale,
             };
             if player_collider.aabb_colided(&collider) {
+                let player_z = self.player.model.transform.position.z;
+                let beat = self.song_time + ((player_z - collider.position.z) / self.speed) as f64;
                 self.player_state = PlayerStatus::Dead;
-                self.death();
+                self.death(beat);
                 return;
             }
         }
@@ -193,6 +349,12 @@ impl SceneState {
             self.pause();
             return;
         }
+        if controller.buttons().contains(&Button::Practice) {
+            self.toggle_practice();
+        }
+        if controller.buttons().contains(&Button::Metronome) {
+            self.toggle_metronome();
+        }
         if let Some(map) = self.map_input(controller) {
             self.load(map);
             return;
@@ -203,7 +365,7 @@ impl SceneState {
         // timing properties
         let dt = delta_time.as_secs_f32();
         let speed_ratio = 0.5;
-        let displacement = speed_ratio * self.speed * dt;
+        let displacement = speed_ratio * self.speed * self.playback_rate * dt;
 
         // lights update
         for light in &mut self.point_lights {
@@ -266,7 +428,7 @@ impl SceneState {
 
     fn resetting_update(&mut self) {
         self.point_lights = Self::starting_lights();
-        self.cubes = Self::starting_cubes(&self.map);
+        self.cubes = Self::starting_cubes(&self.map, self.speed * self.scroll_delay);
         self.player.model.transform.position.x = 0.0;
         self.player.model.transform.position.z = 0.0;
         self.player.target_lane = 1;
@@ -300,19 +462,19 @@ impl SceneState {
                 transform: light1_transform,
                 diffuse: (1.0, 1.0, 0.75),
                 specular: (1.0, 1.0, 0.75),
-                strength: 5.0,
+                strength: LIGHT_STRENGTH,
             };
             let light2 = PointLight {
                 transform: light2_transform,
                 diffuse: (1.0, 1.0, 0.75),
                 specular: (1.0, 1.0, 0.75),
-                strength: 5.0,
+                strength: LIGHT_STRENGTH,
             };
             let light3 = PointLight {
                 transform: light3_transform,
                 diffuse: (1.0, 1.0, 0.75),
                 specular: (1.0, 1.0, 0.75),
-                strength: 5.0,
+                strength: LIGHT_STRENGTH,
             };
             lights.push(light1);
             lights.push(light2);
@@ -322,11 +484,12 @@ impl SceneState {
         lights
     }
 
-    fn starting_cubes(map: &Map) -> Vec<GameObject> {
+    /// `delay` is extra distance before the first cube
+    fn starting_cubes(map: &Map, delay: f32) -> Vec<GameObject> {
         let mut cubes = Vec::with_capacity(64);
         for i in 0..map.beats.len() {
             let (l, m, r) = map.beats[i];
-            let padding = -(map.start_offset + i as f32) * BEAT_SIZE;
+            let padding = -(map.start_offset + i as f32) * BEAT_SIZE - delay;
             if l {
                 cubes.push(GameObject {
                     transform: Transform {
@@ -365,54 +528,184 @@ impl SceneState {
     }
 
     fn play(&mut self) {
-        let action = match self.player_state {
-            PlayerStatus::Alive => TrackAction::Play(self.map.music.clone()),
-            PlayerStatus::Dead => TrackAction::Slow(self.map.music.clone()),
-        };
+        self.muffle_music(OPEN_CUTOFF);
+        // The music kept going under the pause muffle so put it back in time
+        // with the cubes
+        if let PlayerStatus::Alive = self.player_state {
+            let action = TrackAction::Seek(self.map.music().to_string(), self.song_time);
+            let message = AudioMessage::TrackAction(action);
+            self.audio_sender.send(message).unwrap();
+        }
+        let action = TrackAction::Play(self.map.music().to_string());
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
         self.paused = false;
     }
 
     fn escape(&mut self) {
-        let action = TrackAction::Stop(self.map.music.clone());
+        let action = TrackAction::Stop(self.map.music().to_string());
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
         self.menu = true;
     }
 
     fn pause(&mut self) {
-        let action = TrackAction::Stop(self.map.music.clone());
+        self.muffle_music(PAUSE_MUFFLE_CUTOFF);
+        self.paused = true;
+    }
+
+    fn muffle_music(&self, cutoff: f64) {
+        let automation = Automation {
+            param: EffectParam::Cutoff,
+            value: cutoff,
+            ramp: PAUSE_MUFFLE_RAMP,
+        };
+        let action = TrackAction::AutomateEffect(
+            EffectTarget::Bus(Bus::Music),
+            PAUSE_MUFFLE_EFFECT.to_string(),
+            automation,
+        );
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
-        self.paused = true;
     }
 
-    fn death(&mut self) {
+    /// The death sound plays on the beat of the obstacle that was hit, `beat`
+    /// is the song time it reaches the player
+    fn death(&mut self, beat: f64) {
+        let player = self.player.model.transform.position;
+        let position = Vector3::new(player.x, player.y, player.z);
+        let place_death = TrackAction::Position(DEATH_TRACK.to_string(), Some(position));
+        self.audio_sender
+            .send(AudioMessage::TrackAction(place_death))
+            .unwrap();
+        let at = SongTime {
+            track: self.map.music().to_string(),
+            time: beat,
+        };
         let play_death = TrackAction::Play(DEATH_TRACK.to_string());
+        let play_death = TrackAction::Schedule(at.clone(), Box::new(play_death));
         self.audio_sender
             .send(AudioMessage::TrackAction(play_death))
             .unwrap();
-        let action = TrackAction::Slow(self.map.music.clone());
+        self.streak = 0;
+        // Maps split into stems lose the melody first, then the music winds
+        // down from the beat so it gets there at full speed to play the death
+        // sound on time. The cubes slow down in `dead_update` alongside it.
+        self.mute_stem(DROPPED_STEM, true);
+        let tape_stop = PlaybackRate {
+            rate: 0.0,
+            ramp: TAPE_STOP_SECONDS,
+            preserve_pitch: false,
+        };
+        let action = TrackAction::Rate(self.map.music().to_string(), tape_stop);
+        let action = TrackAction::Schedule(at, Box::new(action));
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
         self.player_state = PlayerStatus::Dead;
+        self.deaths += 1;
     }
 
     fn reset(&mut self) {
-        let action = TrackAction::Reset(self.map.music.clone());
+        let action = TrackAction::Reset(self.map.music().to_string());
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
+        self.set_playback_rate(self.playback_rate);
+        self.mute_stem(DROPPED_STEM, false);
+        self.song_time = 0.0;
+        self.streak = 0;
         self.resetting_update();
     }
 
+    fn toggle_practice(&mut self) {
+        let rate = if self.playback_rate < 1.0 {
+            1.0
+        } else {
+            PRACTICE_RATE
+        };
+        self.set_playback_rate(rate);
+    }
+
+    /// Swap in an edited map without leaving the level. The cubes are placed
+    /// where they would be at the current point in the song. The music that
+    /// is already loaded keeps playing.
+    fn reload_map(&mut self, map: Map) {
+        info!(bpm = map.bpm, rows = map.beats.len(), "Map reloaded");
+        let stems = std::mem::replace(&mut self.map, map).stems;
+        self.map.stems = stems;
+        self.speed = BEAT_SIZE * (self.map.bpm / 60.0) * self.map.subdivisions;
+        self.cubes = Self::starting_cubes(&self.map, self.speed * self.scroll_delay);
+        let travelled = self.speed * self.song_time as f32;
+        for cube in &mut self.cubes {
+            cube.transform.position.z += travelled;
+        }
+        if self.metronome {
+            self.send_click_track();
+        }
+    }
+
+    /// Clicks on every beat row so chart timing can be checked against the song
+    fn toggle_metronome(&mut self) {
+        self.metronome = !self.metronome;
+        self.send_click_track();
+    }
+
+    fn send_click_track(&self) {
+        let click_track = self.metronome.then(|| ClickTrack {
+            track: Some(self.map.music().to_string()),
+            bpm: self.map.bpm as f64,
+            subdivisions: self.map.subdivisions as f64,
+            start_offset: self.map.start_offset as f64,
+        });
+        let action = TrackAction::ClickTrack(click_track);
+        let message = AudioMessage::TrackAction(action);
+        self.audio_sender.send(message).unwrap();
+    }
+
+    fn set_playback_rate(&mut self, rate: f32) {
+        self.playback_rate = rate;
+        let playback_rate = PlaybackRate {
+            rate: rate as f64,
+            ramp: 0.0,
+            preserve_pitch: rate != 1.0,
+        };
+        let action = TrackAction::Rate(self.map.music().to_string(), playback_rate);
+        let message = AudioMessage::TrackAction(action);
+        self.audio_sender.send(message).unwrap();
+    }
+
     fn load(&mut self, map: usize) {
-        let action = TrackAction::Reset(self.map.music.clone());
+        let action = TrackAction::Reset(self.map.music().to_string());
         let message = AudioMessage::TrackAction(action);
         self.audio_sender.send(message).unwrap();
         self.change_scene = Some(map);
     }
 
+    /// The stems play inside the lead track so they start together and stay
+    /// in time. Mutes from an earlier play of the map are cleared.
+    fn attach_stems(&mut self) {
+        let music = self.map.music().to_string();
+        for stem in &self.map.stems {
+            if stem.wav != music {
+                let action = TrackAction::Stem(music.clone(), stem.wav.clone());
+                let message = AudioMessage::TrackAction(action);
+                self.audio_sender.send(message).unwrap();
+            }
+            let action = TrackAction::Mute(stem.wav.clone(), false);
+            let message = AudioMessage::TrackAction(action);
+            self.audio_sender.send(message).unwrap();
+        }
+    }
+
+    fn mute_stem(&mut self, name: &str, muted: bool) {
+        let Some(stem) = self.map.stem(name) else {
+            return;
+        };
+        let action = TrackAction::Mute(stem.wav.clone(), muted);
+        let message = AudioMessage::TrackAction(action);
+        self.audio_sender.send(message).unwrap();
+        self.stem_dropped = muted;
+    }
+
     fn map_input(&self, controller: &Controller) -> Option<usize> {
         for button in controller.buttons() {
             match button {
@@ -4,14 +4,18 @@ use std::{sync::{
 }, time::Duration};
 
 use anyhow::Result;
-use tracing::debug;
+use tracing::{debug, error};
 
 use crate::{
     audio::{AudioManager, AudioMessage},
-    render::Renderer,
+    config::LEVEL_MODELS,
+    controller::{Button, Controller},
+    render::RenderMessage,
     resource::{
         manager::{DataResRec, ResourceManager},
         map::Map,
+        progress::{LoadRequest, LoadStage},
+        settings::Settings,
     },
 };
 
@@ -21,8 +25,14 @@ pub struct LoadingState {
     pub progress: f32,
     map: Option<Map>,
     map_receiver: DataResRec<Map>,
+    // Edited versions of the map, passed on to the level
+    map_reloads: Option<DataResRec<Map>>,
+    // Everything the level loads, cancelled if the player backs out
+    request: LoadRequest,
     pub level: Option<SceneState>,
+    pub menu: bool,
     audio_send: Sender<AudioMessage>,
+    settings: Settings,
 }
 
 impl LoadingState {
@@ -30,66 +40,128 @@ impl LoadingState {
         resource_manager: &Arc<ResourceManager>,
         map: String,
         audio_send: Sender<AudioMessage>,
+        render_send: Sender<RenderMessage>,
+        settings: Settings,
     ) -> Self {
         let (map_sender, map_receiver) = mpsc::channel::<(String, Result<Map>)>();
-        resource_manager.load_map(map, map_sender);
+        let (reload_sender, map_reloads) = mpsc::channel::<(String, Result<Map>)>();
+        let request = resource_manager.request();
+        resource_manager.watch::<Map>(&map, reload_sender);
+        resource_manager.load_for::<Map>(&request, map, map_sender);
+        // Usually already loaded globally, the request still waits for them
+        // when a level is started straight away
+        for model in LEVEL_MODELS {
+            request.set_stage(model, LoadStage::Queued);
+            let message = RenderMessage::Load(model.to_string(), Some(request.clone()));
+            render_send.send(message).unwrap();
+        }
         Self {
             progress: 0.0,
             map: None,
             map_receiver,
+            map_reloads: Some(map_reloads),
+            request,
             level: None,
+            menu: false,
             audio_send,
+            settings,
         }
     }
 
-    pub fn update(&mut self, delta_time: &Duration, audio_manager: &AudioManager, renderer: &Renderer) {
-        // timing properties
-        let dt = delta_time.as_secs_f32();
-        let percent_per_second = 0.4;
-        let displacement = percent_per_second * dt;
-        if self.progress < 0.1 {
-            self.progress += displacement;
+    pub fn update(
+        &mut self,
+        delta_time: &Duration,
+        controller: &Controller,
+        audio_manager: &AudioManager,
+    ) {
+        if controller.buttons().contains(&Button::Quit) {
+            self.leave();
+            return;
         }
         if self.map.is_none() {
-            let Ok((_, map)) = self.map_receiver.try_recv() else {
-                return;
-            };
-            let map = map.unwrap();
-            let wavs = [map.music.as_str()];
-            for wav in wavs {
-                self.audio_send
-                    .send(AudioMessage::Load(wav.to_string()))
-                    .unwrap();
-            }
-            self.map = Some(map);
-            debug!("Map Loaded");
-            if self.progress < 0.1 {
-                self.progress = 0.1;
+            if let Ok((name, map)) = self.map_receiver.try_recv() {
+                self.map_loaded(name, map);
             }
         }
+        let Some(map) = &self.map else {
+            self.update_progress(delta_time, false);
+            return;
+        };
 
-        let (loading_audio, loaded_audio) = audio_manager.loaded_check();
-        let (loading_models, loaded_models) = renderer.loaded_check();
-
-        if loading_audio + loading_models == 0 {
-            // TODO: Base this on delta time instead of being frame rate dependant
-            self.progress += displacement;
-        } else {
-            let loaded_assets = loaded_audio + loaded_models;
-            let loading_total = loaded_assets + loading_audio + loading_models;
-            let target_progress = 0.1 + 0.9 * (loaded_assets as f32 / loading_total as f32);
-            let mut delta = target_progress - self.progress;
-            delta = f32::max(delta, 0.0);
-            self.progress += delta * delta * dt;
+        // Without all of its music the level can't be played, go back to the
+        // menu which releases what was loaded
+        for stem in &map.stems {
+            if let Some(e) = audio_manager.load_error(&stem.wav) {
+                error!(err = e.to_string(), music = stem.wav, "Level music failed to load");
+                self.leave();
+                return;
+            }
         }
-        // We want a chance for this to be full
-        if self.progress <= 1.0 + percent_per_second / 2.0 {
+
+        let loaded = self.request.progress().done();
+        if !self.update_progress(delta_time, loaded) {
             return;
         }
-
         self.level = Some(SceneState::new(
             self.map.take().unwrap(),
+            self.map_reloads.take().unwrap(),
             self.audio_send.clone(),
+            &self.settings,
         ));
     }
+
+    fn map_loaded(&mut self, name: String, map: Result<Map>) {
+        let map = match map {
+            Ok(map) => map,
+            Err(e) => {
+                error!(err = format!("{:#}", e), map = name, "Failed to load map");
+                self.leave();
+                return;
+            }
+        };
+        self.request.set_stage(&name, LoadStage::Done);
+        for wav in map.stems.iter().map(|s| &s.wav) {
+            // Followed from now so the request isn't done before the audio
+            // manager has started them
+            self.request.set_stage(wav, LoadStage::Queued);
+            let request = Some(self.request.clone());
+            self.audio_send
+                .send(AudioMessage::Load(wav.to_string(), request))
+                .unwrap();
+        }
+        self.map = Some(map);
+        debug!("Map Loaded");
+    }
+
+    /// Move the bar towards how much of the request has loaded, true once
+    /// everything has loaded and the bar has had a chance to be full
+    fn update_progress(&mut self, delta_time: &Duration, loaded: bool) -> bool {
+        let dt = delta_time.as_secs_f32();
+        let percent_per_second = 0.4;
+        if loaded {
+            self.progress += percent_per_second * dt;
+        } else {
+            let target_progress = self.request.progress().fraction;
+            let delta = f32::max(target_progress - self.progress, 0.0);
+            self.progress += f32::min(delta, percent_per_second * dt);
+        }
+        // We want a chance for this to be full
+        self.progress > 1.0 + percent_per_second / 2.0
+    }
+
+    /// Back to the menu, loads still in progress are cancelled and closing
+    /// the scope releases whatever already loaded
+    fn leave(&mut self) {
+        let progress = self.request.progress();
+        debug!(
+            request = self.request.id().0,
+            loaded = progress.finished,
+            assets = progress.assets,
+            bytes_read = progress.bytes_read,
+            bytes_total = progress.bytes_total,
+            "Leaving loading screen"
+        );
+        self.request.cancel();
+        self.menu = true;
+    }
 }
@@ -29,6 +29,7 @@ pub struct MenuState {
 impl MenuState {
     pub fn new(
         quit_send: Sender<()>,
+        calibrate_send: Sender<()>,
     ) -> Self {
         Self {
             left_padding: 0.3,
@@ -51,6 +52,13 @@ impl MenuState {
                 },
             ],
             additional_buttons: vec![
+                Button {
+                    name: "Calibrate".to_string(),
+                    hover_time: 0.0,
+                    callback: Box::new(move || {
+                        calibrate_send.send(()).unwrap();
+                    }),
+                },
                 Button {
                     name: "Quit".to_string(),
                     hover_time: 0.0,
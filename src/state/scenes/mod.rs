@@ -1,28 +1,39 @@
+pub mod calibration;
 pub mod level;
 pub mod loading;
 pub mod menu;
+pub mod results;
 
 use std::{
+    path::PathBuf,
     sync::{
-        mpsc::Sender,
+        mpsc::{self, Receiver, Sender},
         Arc,
     },
     time::Duration,
 };
 
+use tracing::error;
+
+use calibration::CalibrationState;
 use level::SceneState;
 use loading::LoadingState;
 use menu::MenuState;
+use results::ResultsState;
 use na::Matrix4;
 
 use crate::{
-    audio::{AudioManager, AudioMessage},
+    audio::{AudioManager, AudioMessage, Bus, EffectKind, EffectTarget, TrackAction},
     config::{
-        BACKPACK_MODEL, CUBE_MODEL, DEATH_TRACK, PLANE_LENGTH, PLANE_MODEL, SAD_MAP, UPBEAT_MAP,
+        DEATH_TRACK, LEVEL_MODELS, OPEN_CUTOFF, PAUSE_MUFFLE_EFFECT, PLANE_LENGTH, SAD_MAP,
+        UPBEAT_MAP,
     },
     controller::Controller,
-    render::{RenderMessage, Renderer},
-    resource::manager::ResourceManager,
+    render::RenderMessage,
+    resource::{
+        manager::ResourceManager,
+        settings::{self, Settings},
+    },
     shader,
 };
 
@@ -31,13 +42,21 @@ pub struct SceneManager {
     resource_manager: Arc<ResourceManager>,
     maps: Vec<String>,
     audio_send: Sender<AudioMessage>,
+    render_send: Sender<RenderMessage>,
     quit_send: Sender<()>,
+    calibrate_send: Sender<()>,
+    calibrate_rec: Receiver<()>,
+    settings: Settings,
+    // Where the settings are saved
+    user_data: PathBuf,
 }
 
 enum Scene {
     Level(SceneState),
     Loading(LoadingState),
     Menu(MenuState),
+    Results(ResultsState),
+    Calibration(CalibrationState),
 }
 
 impl SceneManager {
@@ -50,26 +69,44 @@ impl SceneManager {
         let maps = vec![SAD_MAP.to_string(), UPBEAT_MAP.to_string()];
 
         audio_send
-            .send(AudioMessage::Load(DEATH_TRACK.to_string()))
+            .send(AudioMessage::Load(DEATH_TRACK.to_string(), None))
             .unwrap();
-
-        let global_models = [
-            CUBE_MODEL.to_string(),
-            PLANE_MODEL.to_string(),
-            BACKPACK_MODEL.to_string(),
+        let global_audio = [
+            TrackAction::Route(DEATH_TRACK.to_string(), Bus::Sfx),
+            TrackAction::AddEffect(
+                EffectTarget::Bus(Bus::Music),
+                PAUSE_MUFFLE_EFFECT.to_string(),
+                EffectKind::LowPass {
+                    cutoff: OPEN_CUTOFF,
+                    q: 0.707,
+                },
+            ),
         ];
+        for action in global_audio {
+            audio_send.send(AudioMessage::TrackAction(action)).unwrap();
+        }
 
-        for model in global_models {
-            render_send.send(RenderMessage::Load(model)).unwrap();
+        // Loaded before any scope is open so they are kept for the whole game
+        for model in LEVEL_MODELS {
+            render_send
+                .send(RenderMessage::Load(model.to_string(), None))
+                .unwrap();
         }
-        let menu = MenuState::new(quit_send.clone());
+        let (calibrate_send, calibrate_rec) = mpsc::channel();
+        let user_data = settings::user_data_dir();
+        let menu = MenuState::new(quit_send.clone(), calibrate_send.clone());
         //let loading = LoadingState::new(&resource_manager, maps[0].clone(), audio_send.clone());
         Self {
             resource_manager,
             maps,
             scene: Scene::Menu(menu),
             audio_send,
+            render_send,
             quit_send,
+            calibrate_send,
+            calibrate_rec,
+            settings: Settings::load_or_default(&user_data),
+            user_data,
         }
     }
 
@@ -78,50 +115,109 @@ impl SceneManager {
         delta_time: &Duration,
         controller: &Controller,
         audio_manager: &AudioManager,
-        renderer: &Renderer,
     ) {
         match &mut self.scene {
             Scene::Level(l) => {
+                l.track_events(audio_manager.events());
+                l.music_analysis(audio_manager.analysis());
                 l.update(delta_time, controller);
                 if l.menu {
-                    let menu = MenuState::new(self.quit_send.clone());
+                    self.close_scope();
+                    let menu = MenuState::new(self.quit_send.clone(), self.calibrate_send.clone());
                     self.scene = Scene::Menu(menu);
                 } else if let Some(s) = l.change_scene {
+                    self.close_scope();
+                    self.open_scope();
                     let loading = LoadingState::new(
                         &self.resource_manager,
                         self.maps[s].clone(),
                         self.audio_send.clone(),
+                        self.render_send.clone(),
+                        self.settings,
                     );
                     self.scene = Scene::Loading(loading);
+                } else if l.finished {
+                    let results = ResultsState::new(l.deaths);
+                    self.close_scope();
+                    self.scene = Scene::Results(results);
                 }
             }
             Scene::Loading(l) => {
-                l.update(delta_time, audio_manager, renderer);
+                l.update(delta_time, controller, audio_manager);
                 if let Some(level) = l.level.take() {
                     self.scene = Scene::Level(level);
+                } else if l.menu {
+                    self.close_scope();
+                    let menu = MenuState::new(self.quit_send.clone(), self.calibrate_send.clone());
+                    self.scene = Scene::Menu(menu);
                 }
             },
             Scene::Menu(m) => {
                 m.update(delta_time, controller);
-                if let Some(map) = &m.loading_scene {
-                    let loading = LoadingState::new(&self.resource_manager, map.clone(), self.audio_send.clone());
+                if let Some(map) = m.loading_scene.clone() {
+                    self.open_scope();
+                    let loading = LoadingState::new(
+                        &self.resource_manager,
+                        map,
+                        self.audio_send.clone(),
+                        self.render_send.clone(),
+                        self.settings,
+                    );
                     self.scene = Scene::Loading(loading);
+                } else if self.calibrate_rec.try_recv().is_ok() {
+                    let calibration = CalibrationState::new(self.audio_send.clone());
+                    self.scene = Scene::Calibration(calibration);
+                }
+            }
+            Scene::Results(r) => {
+                r.update(delta_time, controller);
+                if r.menu {
+                    let menu = MenuState::new(self.quit_send.clone(), self.calibrate_send.clone());
+                    self.scene = Scene::Menu(menu);
+                }
+            }
+            Scene::Calibration(c) => {
+                c.update(controller);
+                if let Some(settings) = c.result.take() {
+                    if let Err(e) = settings.save(&self.user_data) {
+                        error!(err = e.to_string(), "Failed to save settings");
+                    }
+                    self.settings = settings;
+                    c.menu = true;
+                }
+                if c.menu {
+                    let menu = MenuState::new(self.quit_send.clone(), self.calibrate_send.clone());
+                    self.scene = Scene::Menu(menu);
                 }
             }
         }
     }
 
+    /// Everything a level loads is released together when it is left, assets
+    /// also loaded globally are kept
+    fn open_scope(&self) {
+        self.audio_send.send(AudioMessage::OpenScope).unwrap();
+        self.render_send.send(RenderMessage::OpenScope).unwrap();
+    }
+
+    fn close_scope(&self) {
+        self.audio_send.send(AudioMessage::CloseScope).unwrap();
+        self.render_send.send(RenderMessage::CloseScope).unwrap();
+    }
+
     pub fn get_level_state<'a>(&'a self) -> Option<&'a SceneState> {
         match &self.scene {
             Scene::Level(l) => Some(l),
             Scene::Loading(_) => None,
             Scene::Menu(_) => None,
+            Scene::Results(_) => None,
+            Scene::Calibration(_) => None,
         }
     }
 
     pub fn get_ui_elements(&self) -> Vec<UiElement> {
         match &self.scene {
-            Scene::Level(_) => Vec::new(),
+            Scene::Level(l) => l.get_ui_elements(),
             Scene::Loading(l) => vec![UiElement {
                 transform: Transform {
                     position: (-0.5, -0.5, 0.0).into(),
@@ -135,6 +231,8 @@ impl SceneManager {
                 merge_amount: 0.0,
             }],
             Scene::Menu(m) => m.get_ui_elements(),
+            Scene::Results(r) => r.get_ui_elements(),
+            Scene::Calibration(c) => c.get_ui_elements(),
         }
     }
 }
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use na::Matrix4;
+
+use crate::controller::{Button, Controller};
+
+use super::{Transform, UiElement};
+
+/// Shown when the song finishes with the player still alive
+pub struct ResultsState {
+    deaths: u32,
+    time: f32,
+    pub menu: bool,
+}
+
+impl ResultsState {
+    pub fn new(deaths: u32) -> Self {
+        Self {
+            deaths,
+            time: 0.0,
+            menu: false,
+        }
+    }
+
+    pub fn update(&mut self, delta_time: &Duration, controller: &Controller) {
+        self.time += delta_time.as_secs_f32();
+        let buttons = controller.buttons();
+        if controller.mouse_click()
+            || buttons.contains(&Button::Quit)
+            || buttons.contains(&Button::Pause)
+        {
+            self.menu = true;
+        }
+    }
+
+    pub fn get_ui_elements(&self) -> Vec<UiElement> {
+        let reveal_seconds = 1.5;
+        let mut result = vec![UiElement {
+            transform: Transform {
+                position: (-0.5, 0.2, 0.0).into(),
+                scale: (1.0, 0.2, 1.0).into(),
+                rotation: Matrix4::identity(),
+            },
+            base_color: (0.1, 0.1, 0.1),
+            progress_color: (0.1, 0.9, 0.1),
+            progress: f32::min(self.time / reveal_seconds, 1.0),
+            merge_color: (0.0, 0.0, 0.0),
+            merge_amount: 0.0,
+        }];
+
+        // One square per death it took to finish the song
+        let max_shown = 10;
+        let size = 0.08;
+        let gap = 0.02;
+        for i in 0..u32::min(self.deaths, max_shown) {
+            result.push(UiElement {
+                transform: Transform {
+                    position: (-0.5 + i as f32 * (size + gap), 0.0, 0.0).into(),
+                    scale: (size, size, 1.0).into(),
+                    rotation: Matrix4::identity(),
+                },
+                base_color: (0.9, 0.1, 0.1),
+                progress_color: (0.0, 0.0, 0.0),
+                progress: 0.0,
+                merge_color: (0.0, 0.0, 0.0),
+                merge_amount: 0.0,
+            });
+        }
+        result
+    }
+}